@@ -0,0 +1,171 @@
+//! Convert between sitelen Lasina and sitelen pona with the dictionaries of the input method.
+mod markdown;
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    process::ExitCode,
+};
+
+use ajemi::engine::{Controls, Conversion, Engine, Names};
+use markdown::Part;
+
+const USAGE: &str = "\
+Usage: ajemi-convert [OPTIONS] [FILE]...
+
+Convert the sitelen Lasina text in the files, or in stdin if no file is given, into sitelen pona
+and write it to stdout. Words that can't be converted are reported to stderr.
+
+Options:
+  -s, --schema <NAME>  Convert with the schema NAME instead of the default one
+  -m, --markdown       Leave front matter, code, URLs and link targets alone
+  -c, --cartouche      Spell names in cartouches instead of keeping them
+  -r, --reverse        Convert sitelen pona back into sitelen Lasina
+  -a, --annotate       Write long glyphs and joiners as they are typed when converting back
+  -q, --quiet          Don't report the words that can't be converted
+  -h, --help           Print this help
+";
+
+#[derive(Default)]
+struct Options {
+    schema: Option<String>,
+    markdown: bool,
+    names: Names,
+    reverse: bool,
+    controls: Controls,
+    quiet: bool,
+    files: Vec<String>,
+}
+
+/// Returns `None` if the help is asked for.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--schema" => {
+                let schema = args
+                    .next()
+                    .ok_or(format!("Missing the schema name after {arg}."))?;
+                options.schema = Some(schema);
+            }
+            "-m" | "--markdown" => options.markdown = true,
+            "-c" | "--cartouche" => options.names = Names::Cartouche,
+            "-r" | "--reverse" => options.reverse = true,
+            "-a" | "--annotate" => options.controls = Controls::Annotate,
+            "-q" | "--quiet" => options.quiet = true,
+            "-h" | "--help" => return Ok(None),
+            "-" => options.files.push(arg),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}.")),
+            _ => options.files.push(arg),
+        }
+    }
+    if options.files.is_empty() {
+        options.files.push("-".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    // dictionaries that fail to load are reported through the log
+    let _ = fern::Dispatch::new()
+        .level(log::LevelFilter::Warn)
+        .chain(io::stderr())
+        .apply();
+    let mut engine = match Engine::build() {
+        Ok(engine) => engine,
+        Err(err) => {
+            eprintln!("Failed to load the dictionaries. {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(schema) = options.schema.as_deref()
+        && !engine.select_schema(schema)
+    {
+        eprintln!("Schema '{schema}' is not found.");
+        return ExitCode::from(2);
+    }
+    let mut stdout = io::stdout().lock();
+    let mut code = ExitCode::SUCCESS;
+    for file in options.files.iter() {
+        let (name, text) = match read(file) {
+            Ok(text) => (if file == "-" { "<stdin>" } else { file }, text),
+            Err(err) => {
+                eprintln!("{file}: {err}");
+                code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        let conversion = convert(&engine, &text, &options);
+        if let Err(err) = stdout.write_all(conversion.output.as_bytes()) {
+            eprintln!("Failed to write the output. {err}");
+            return ExitCode::FAILURE;
+        }
+        if !options.quiet {
+            for unconverted in conversion.unconverted {
+                eprintln!("{name}:{unconverted}");
+            }
+        }
+    }
+    code
+}
+
+fn read(file: &str) -> io::Result<String> {
+    if file == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        Ok(text)
+    } else {
+        fs::read_to_string(file)
+    }
+}
+
+fn convert(engine: &Engine, text: &str, options: &Options) -> Conversion {
+    if !options.markdown {
+        return match options.reverse {
+            true => Conversion {
+                output: engine.reverse(text, options.controls),
+                unconverted: Vec::new(),
+            },
+            false => engine.convert(text, options.names),
+        };
+    }
+    let parts = markdown::split(text);
+    if options.reverse {
+        let mut output = String::with_capacity(text.len());
+        for part in parts {
+            match part {
+                Part::Verbatim(part) => output.push_str(part),
+                Part::Text(part) => output.push_str(&engine.reverse(part, options.controls)),
+            }
+        }
+        return Conversion {
+            output,
+            unconverted: Vec::new(),
+        };
+    }
+    // the markup is kept in place, for sentences and quotes to go on around it
+    let mut kept = Vec::new();
+    let mut offset = 0;
+    for part in parts {
+        let part = match part {
+            Part::Verbatim(part) => {
+                kept.push(offset..offset + part.len());
+                part
+            }
+            Part::Text(part) => part,
+        };
+        offset += part.len();
+    }
+    engine.convert_keeping(text, &kept, options.names)
+}
@@ -0,0 +1,316 @@
+//----------------------------------------------------------------------------
+//
+//  Markdown documents are split into the text to convert and the parts to
+//  leave alone: front matter, code, URLs, link targets and the syntax itself.
+//
+//----------------------------------------------------------------------------
+
+/// A part of a markdown document. The parts put together make up the document again.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Part<'a> {
+    Text(&'a str),
+    Verbatim(&'a str),
+}
+
+/// Splits the document into parts, merging the adjacent ones of the same kind.
+struct Parts<'a> {
+    doc: &'a str,
+    parts: Vec<Part<'a>>,
+    start: usize,
+    end: usize,
+    verbatim: bool,
+}
+
+impl<'a> Parts<'a> {
+    /// Mark the text from the end of the last mark up to `to`.
+    fn mark(&mut self, to: usize, verbatim: bool) {
+        if to <= self.end {
+            return;
+        }
+        if self.start != self.end && verbatim != self.verbatim {
+            self.flush();
+        }
+        self.verbatim = verbatim;
+        self.end = to;
+    }
+
+    fn flush(&mut self) {
+        let text = &self.doc[self.start..self.end];
+        self.parts.push(match self.verbatim {
+            true => Part::Verbatim(text),
+            false => Part::Text(text),
+        });
+        self.start = self.end;
+    }
+
+    fn finish(mut self) -> Vec<Part<'a>> {
+        if self.start != self.end {
+            self.flush();
+        }
+        self.parts
+    }
+}
+
+pub fn split(doc: &str) -> Vec<Part<'_>> {
+    let mut parts = Parts {
+        doc,
+        parts: Vec::new(),
+        start: 0,
+        end: 0,
+        verbatim: true,
+    };
+    let mut pos = front_matter(doc);
+    parts.mark(pos, true);
+    // the char and the length of the fence of the code block
+    let mut fence: Option<(u8, usize)> = None;
+    for line in doc[pos..].split_inclusive('\n') {
+        let end = pos + line.len();
+        let trimmed = line.trim_start_matches(' ');
+        let indented = line.len() - trimmed.len() >= 4;
+        if let Some((ch, len)) = fence {
+            let run = run_len(trimmed.as_bytes(), 0, ch);
+            if !indented && run >= len && trimmed[run..].trim().is_empty() {
+                fence = None;
+            }
+            parts.mark(end, true);
+        } else if !indented && let Some(opening) = opening_fence(trimmed) {
+            fence = Some(opening);
+            parts.mark(end, true);
+        } else if is_verbatim_line(trimmed) {
+            parts.mark(end, true);
+        } else {
+            let from = pos + prefix_len(line);
+            parts.mark(from, true);
+            inline(&mut parts, from, end);
+        }
+        pos = end;
+    }
+    parts.finish()
+}
+
+/// The length of the YAML or TOML front matter, or 0 if there's none.
+fn front_matter(doc: &str) -> usize {
+    let mut lines = doc.split_inclusive('\n');
+    let Some(first) = lines.next() else {
+        return 0;
+    };
+    let delimiter = first.trim_end();
+    if delimiter != "---" && delimiter != "+++" {
+        return 0;
+    }
+    let mut len = first.len();
+    for line in lines {
+        len += line.len();
+        if line.trim_end() == delimiter {
+            return len;
+        }
+    }
+    0
+}
+
+fn run_len(bytes: &[u8], from: usize, ch: u8) -> usize {
+    bytes[from..].iter().take_while(|byte| **byte == ch).count()
+}
+
+fn opening_fence(line: &str) -> Option<(u8, usize)> {
+    let ch = *line.as_bytes().first()?;
+    if ch != b'`' && ch != b'~' {
+        return None;
+    }
+    let len = run_len(line.as_bytes(), 0, ch);
+    (len >= 3).then_some((ch, len))
+}
+
+/// Thematic breaks, setext underlines, HTML and link reference definitions
+fn is_verbatim_line(line: &str) -> bool {
+    let line = line.trim_end();
+    !line.is_empty()
+        && line
+            .chars()
+            .all(|ch| matches!(ch, '-' | '*' | '_' | '=' | ' '))
+        || line.starts_with('<')
+        || line.starts_with('[') && line.contains("]:")
+}
+
+/// The length of the block quote markers, list markers and heading markers the line starts with.
+fn prefix_len(line: &str) -> usize {
+    let bytes = line.as_bytes();
+    let spaces = |i: usize| {
+        bytes[i..]
+            .iter()
+            .take_while(|b| **b == b' ' || **b == b'\t')
+            .count()
+    };
+    let mut i = spaces(0);
+    loop {
+        let digits = bytes[i..].iter().take_while(|b| b.is_ascii_digit()).count();
+        let marker = match bytes.get(i) {
+            Some(b'>') => 1,
+            Some(b'-' | b'*' | b'+') => 1,
+            Some(b'#') => run_len(bytes, i, b'#'),
+            Some(b'0'..=b'9') if matches!(bytes.get(i + digits), Some(b'.' | b')')) => digits + 1,
+            _ => break,
+        };
+        // markers other than `>` are followed by a space
+        let space = spaces(i + marker);
+        if bytes[i] != b'>' && space == 0 && i + marker != bytes.len() {
+            break;
+        }
+        i += marker + space;
+        // task lists
+        for task in ["[ ] ", "[x] ", "[X] "] {
+            if line[i..].starts_with(task) {
+                i += task.len();
+            }
+        }
+    }
+    i
+}
+
+/// Mark the code spans, URLs, links and emphasis in the line.
+fn inline(parts: &mut Parts, from: usize, to: usize) {
+    let doc = parts.doc;
+    let line = &doc[from..to];
+    let bytes = line.as_bytes();
+    // where the text of the links end and where their targets end
+    let mut links: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let verbatim = match bytes[i] {
+            _ if links.last().is_some_and(|(text, _)| *text == i) => {
+                links.pop().map(|(_, target)| target)
+            }
+            b'\\' => line[i + 1..].chars().next().map(|ch| i + 1 + ch.len_utf8()),
+            b'`' => {
+                let len = run_len(bytes, i, b'`');
+                Some(code_span(bytes, i, len).unwrap_or(i + len))
+            }
+            b'<' if bytes
+                .get(i + 1)
+                .is_some_and(|b| b.is_ascii_alphabetic() || matches!(b, b'/' | b'!')) =>
+            {
+                line[i..].find('>').map(|end| i + end + 1)
+            }
+            b'*' | b'_' => Some(i + run_len(bytes, i, bytes[i])),
+            b'!' if bytes.get(i + 1) == Some(&b'[') => link(bytes, i + 1).map(|link| {
+                links.push(link);
+                i + 2
+            }),
+            b'[' => link(bytes, i).map(|link| {
+                links.push(link);
+                i + 1
+            }),
+            b'h' | b'm' | b'w' if i == 0 || !bytes[i - 1].is_ascii_alphanumeric() => url(line, i),
+            _ => None,
+        };
+        match verbatim {
+            Some(end) => {
+                parts.mark(from + i, false);
+                parts.mark(from + end, true);
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    parts.mark(to, false);
+}
+
+/// The end of the code span starting with `len` backticks at `from`.
+fn code_span(bytes: &[u8], from: usize, len: usize) -> Option<usize> {
+    let mut i = from + len;
+    while i < bytes.len() {
+        let run = run_len(bytes, i, b'`');
+        if run == len {
+            return Some(i + run);
+        }
+        i += run.max(1);
+    }
+    None
+}
+
+/// Where the text of the link starting at `from` ends, and where its target ends.
+fn link(bytes: &[u8], from: usize) -> Option<(usize, usize)> {
+    let text = matching(bytes, from, b'[', b']')?;
+    let target = match bytes.get(text + 1)? {
+        b'(' => matching(bytes, text + 1, b'(', b')')?,
+        b'[' => matching(bytes, text + 1, b'[', b']')?,
+        _ => return None,
+    };
+    Some((text, target + 1))
+}
+
+/// The index of the bracket closing the one at `from`.
+fn matching(bytes: &[u8], from: usize, open: u8, close: u8) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = from;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            byte if byte == open => depth += 1,
+            byte if byte == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The end of the URL starting at `from`, without the punctuators after it.
+fn url(line: &str, from: usize) -> Option<usize> {
+    let rest = &line[from..];
+    if !["http://", "https://", "mailto:", "www."]
+        .iter()
+        .any(|scheme| rest.starts_with(scheme))
+    {
+        return None;
+    }
+    let len = rest
+        .find(|ch: char| ch.is_whitespace() || ch == '<')
+        .unwrap_or(rest.len());
+    let url = rest[..len].trim_end_matches(['.', ',', ':', ';', '!', '?', ')', '"', '\'']);
+    Some(from + url.len())
+}
+
+#[test]
+fn test_split() {
+    use Part::*;
+    let doc = "\
+---
+title: toki
+---
+# toki pona
+- mi `jan` li lukin e [lipu](https://example.com/lipu) *pona*.
+```
+toki
+```
+ni li [jan Sonja] https://tokipona.org.
+";
+    assert_eq!(
+        split(doc),
+        [
+            Verbatim("---\ntitle: toki\n---\n# "),
+            Text("toki pona\n"),
+            Verbatim("- "),
+            Text("mi "),
+            Verbatim("`jan`"),
+            Text(" li lukin e "),
+            Verbatim("["),
+            Text("lipu"),
+            Verbatim("](https://example.com/lipu)"),
+            Text(" "),
+            Verbatim("*"),
+            Text("pona"),
+            Verbatim("*"),
+            Text(".\n"),
+            Verbatim("```\ntoki\n```\n"),
+            Text("ni li [jan Sonja] "),
+            Verbatim("https://tokipona.org"),
+            Text(".\n"),
+        ]
+    );
+}
@@ -1,8 +1,17 @@
-use std::{env, fs, path::PathBuf, sync::OnceLock};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::PathBuf,
+    sync::OnceLock,
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, de::Error as _};
 
-use crate::{DEFAULT_CONF, Error, IME_NAME, Result, extend::ResultExt};
+use crate::{
+    DEFAULT_CONF, Error, IME_NAME, Result, engine,
+    extend::ResultExt,
+    keys::{Keys, TapKey},
+};
 
 // use parking_lot::{RwLock, RwLockReadGuard};
 //
@@ -28,8 +37,27 @@ pub fn get() -> &'static Conf {
 pub struct Conf {
     pub font: Font,
     pub layout: Layout,
+    #[serde(default)]
+    pub candidates: Candidates,
     pub color: Color,
     pub behavior: Behavior,
+    #[serde(default)]
+    pub keys: Keys,
+    #[serde(default)]
+    pub mixed: Mixed,
+    pub lookup: Option<Lookup>,
+    #[serde(default)]
+    pub output: Output,
+    #[serde(default)]
+    pub ligature: Ligature,
+    #[serde(default)]
+    pub long_glyph: LongGlyph,
+    /// Settings of individual schemas, keyed by their names
+    #[serde(default)]
+    pub schema: HashMap<String, SchemaConf>,
+    /// Output profiles for fonts that are not UCSUR-compliant, keyed by their names
+    #[serde(default)]
+    pub profile: HashMap<String, Profile>,
 }
 
 impl Default for Conf {
@@ -49,7 +77,14 @@ impl Conf {
             return Ok(Conf::default());
         }
         let conf = fs::read_to_string(path)?;
-        let conf = toml::from_str(&conf).map_err(|e| Error::ParseError("conf.toml", e))?;
+        let conf: Conf = toml::from_str(&conf).map_err(|e| Error::ParseError("conf.toml", e))?;
+        let puncts = engine::puncts()?;
+        conf.candidates
+            .validate(conf.lookup.as_ref(), &puncts)
+            .map_err(|e| Error::ConfInvalid("candidates", e))?;
+        conf.keys
+            .validate(&conf.candidates)
+            .map_err(|e| Error::ConfInvalid("keys", e))?;
         Ok(conf)
     }
 
@@ -62,6 +97,19 @@ impl Conf {
 pub struct Font {
     pub name: String,
     pub size: i32,
+    /// Path to the font file. Looked up by `name` if absent.
+    pub path: Option<String>,
+    #[serde(default)]
+    pub unsupported: Unsupported,
+}
+
+/// What to do with candidates the font can't render
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub enum Unsupported {
+    Keep,
+    #[default]
+    Demote,
+    Hide,
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,11 +127,355 @@ pub struct Layout {
     pub vertical: bool,
 }
 
+/// The candidates on a page of the candidate list and the keys selecting them
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Candidates {
+    /// How many candidates a page holds, up to 9
+    pub page_size: usize,
+    /// The keys selecting the candidates on a page, in order
+    pub keys: String,
+    /// The modifier to hold while pressing the keys
+    pub modifier: Modifier,
+    /// Shown before the candidates on a page, in order. The keys are shown if empty.
+    pub labels: Vec<String>,
+    /// Shown after the labels, without the trailing spaces if the font is monospace
+    pub suffix: String,
+}
+
+impl Default for Candidates {
+    fn default() -> Self {
+        Candidates {
+            page_size: 5,
+            keys: "12345".to_string(),
+            modifier: Modifier::None,
+            labels: Vec::new(),
+            suffix: ". ".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    #[default]
+    None,
+    Ctrl,
+    Alt,
+}
+
+impl Candidates {
+    /// The candidate on the page selected by the key, if it's pressed without modifiers.
+    pub fn select(&self, key: char) -> Option<usize> {
+        if self.modifier != Modifier::None {
+            return None;
+        }
+        self.keys
+            .chars()
+            .take(self.page_size)
+            .position(|k| k == key)
+    }
+
+    /// The candidate on the page selected by the key, if it's pressed with the modifier.
+    pub fn select_held(&self, modifier: Modifier, key: char) -> Option<usize> {
+        if modifier == Modifier::None || modifier != self.modifier {
+            return None;
+        }
+        self.keys
+            .chars()
+            .take(self.page_size)
+            .position(|k| k.eq_ignore_ascii_case(&key))
+    }
+
+    /// The label of the candidate on the page.
+    pub fn label(&self, index: usize) -> String {
+        match self.labels.get(index) {
+            Some(label) => label.clone(),
+            None => self
+                .keys
+                .chars()
+                .nth(index)
+                .map(String::from)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Make sure each candidate on a page has a key, and that the keys don't take the place of
+    /// what is typed while composing, including the punctuators of the schemas.
+    fn validate(
+        &self,
+        lookup: Option<&Lookup>,
+        puncts: &HashSet<char>,
+    ) -> std::result::Result<(), String> {
+        if !(1..=9).contains(&self.page_size) {
+            return Err(format!("page_size is {}, not from 1 to 9.", self.page_size));
+        }
+        let keys: Vec<char> = self.keys.chars().collect();
+        if keys.len() < self.page_size {
+            return Err(format!(
+                "{} keys are needed for the page size.",
+                self.page_size
+            ));
+        }
+        if !self.labels.is_empty() && self.labels.len() < self.page_size {
+            return Err(format!(
+                "{} labels are needed for the page size.",
+                self.page_size
+            ));
+        }
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                return Err(format!("Key '{key}' is given twice."));
+            }
+            let conflicting = match self.modifier {
+                // they are told apart by the keys pressed along with the modifiers
+                Modifier::Ctrl | Modifier::Alt => !key.is_ascii_alphanumeric(),
+                // letters are spelled, and punctuators re-mapped or paired up
+                Modifier::None => {
+                    key.is_alphabetic()
+                        || key.is_whitespace()
+                        || puncts.contains(key)
+                        || lookup.is_some_and(|lookup| lookup.prefix == *key)
+                }
+            };
+            if conflicting {
+                return Err(format!(
+                    "Key '{key}' can't select candidates with modifier {:?}.",
+                    self.modifier
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Behavior {
     pub toggle: Option<Toggle>,
     pub long_pi: bool,
     pub long_glyph: bool,
+    /// Keep sentence punctuators in the composition instead of committing at them
+    #[serde(default)]
+    pub multiple_sentences: bool,
+    /// Insert the closer right after typing a cartouche or long glyph opener
+    #[serde(default)]
+    pub auto_close: bool,
+    #[serde(default)]
+    pub backspace: Backspace,
+    /// Bring the glyphs just committed back into a composition by Backspace
+    #[serde(default)]
+    pub recompose: bool,
+    /// For how many seconds the last commit can be undone
+    #[serde(default = "default_undo_window")]
+    pub undo_window: u64,
+    /// For how many milliseconds the toggle can be held down to count as a tap
+    #[serde(default = "default_tap_timeout")]
+    pub tap_timeout: u64,
+}
+
+fn default_undo_window() -> u64 {
+    10
+}
+
+fn default_tap_timeout() -> u64 {
+    500
+}
+
+/// What Backspace takes back in a composition
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backspace {
+    /// The last letter
+    #[default]
+    Letter,
+    /// The last candidate selected, if nothing is typed after it, or the last letter otherwise
+    Selection,
+}
+
+/// Candidates from other schemas shown at the end of the first page, after the ones from the
+/// active schema.
+#[derive(Deserialize, Debug)]
+pub struct Mixed {
+    pub enabled: bool,
+    pub schemas: Vec<String>,
+    pub candidates: usize,
+}
+
+impl Default for Mixed {
+    fn default() -> Self {
+        Mixed {
+            enabled: false,
+            schemas: vec!["emoji".to_string()],
+            candidates: 1,
+        }
+    }
+}
+
+/// Typing `prefix` starts a composition that is looked up in `schema` only once.
+#[derive(Deserialize, Debug)]
+pub struct Lookup {
+    pub prefix: char,
+    pub schema: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Output {
+    pub mode: Mode,
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub separator: Separator,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Commit the code points of the glyphs
+    #[default]
+    Ucsur,
+    /// Commit Latin words for fonts that render them as ligatures
+    Ligature,
+}
+
+/// What goes between committed words so that editors can break lines there
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Separator {
+    #[default]
+    None,
+    /// Zero width space
+    Zwsp,
+    Space,
+    /// A space between sentences only
+    Sentence,
+}
+
+/// Words that extend into long glyphs when `long_glyph` is enabled, looked up in each schema
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LongGlyph {
+    /// Words extending over the phrase after them
+    pub forward: Vec<String>,
+    /// Words extending over the phrase they end
+    pub backward: Vec<String>,
+    /// Words extending over the whole clause before them
+    pub clause: Vec<String>,
+    /// Words extending over both sides of questions like "x ala x"
+    pub question: Vec<String>,
+    /// Words ending the phrases extended over
+    pub particles: Vec<String>,
+}
+
+impl Default for LongGlyph {
+    fn default() -> Self {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+        LongGlyph {
+            forward: words(&["awen", "ken", "kepeken", "lon", "tawa"]),
+            backward: words(&["kama"]),
+            clause: words(&["la"]),
+            question: words(&["ala"]),
+            particles: words(&["li", "e", "o", "en", "anu"]),
+        }
+    }
+}
+
+/// How joiners and brackets are written for ligature fonts
+#[derive(Deserialize, Debug)]
+pub struct Ligature {
+    pub zwj: String,
+    pub stack: String,
+    pub scale: String,
+    pub cartouche: (String, String),
+    pub long_glyph: (String, String),
+    pub reverse_long_glyph: (String, String),
+}
+
+impl Default for Ligature {
+    fn default() -> Self {
+        let pair = |open: &str, close: &str| (open.to_string(), close.to_string());
+        Ligature {
+            zwj: "-".to_string(),
+            stack: "^".to_string(),
+            scale: "+".to_string(),
+            cartouche: pair("[", "]"),
+            long_glyph: pair("(", ")"),
+            reverse_long_glyph: pair("{", "}"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SchemaConf {
+    pub mode: Option<Mode>,
+    pub profile: Option<String>,
+}
+
+/// Maps the UCSUR output to the code points a font actually supports.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Profile {
+    /// Replacements of individual code points. Empty replacements drop the code points.
+    pub map: HashMap<CodePoint, CodePoints>,
+    /// Moves the code points within `[from, to]` so that `from` lands on `onto`
+    pub shift: Vec<(CodePoint, CodePoint, CodePoint)>,
+    /// What to do with the stacking and scaling joiners
+    pub joiner: Fallback,
+    /// Keep the long glyph controls or not
+    pub long_glyph: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            map: HashMap::new(),
+            shift: Vec::new(),
+            joiner: Fallback::Keep,
+            long_glyph: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+pub enum Fallback {
+    #[default]
+    Keep,
+    Zwj,
+    Drop,
+}
+
+/// A character written as itself or as `U+XXXX`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CodePoint(pub char);
+
+/// Characters written as themselves or as `U+XXXX`, separated by spaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodePoints(pub String);
+
+fn parse_code_points(text: &str) -> Option<String> {
+    let mut parsed = String::new();
+    for token in text.split_whitespace() {
+        match token.strip_prefix("U+") {
+            Some(code) => parsed.push(char::from_u32(u32::from_str_radix(code, 16).ok()?)?),
+            None => parsed.push_str(token),
+        }
+    }
+    Some(parsed)
+}
+
+impl<'de> Deserialize<'de> for CodePoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let parsed = parse_code_points(&text).unwrap_or_default();
+        let mut chars = parsed.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Ok(CodePoint(ch)),
+            _ => Err(D::Error::custom(format!("'{text}' is not a code point"))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CodePoints {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        parse_code_points(&text)
+            .map(CodePoints)
+            .ok_or_else(|| D::Error::custom(format!("'{text}' contains invalid code points")))
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Copy)]
@@ -92,6 +484,57 @@ pub enum Toggle {
     Eisu,
     Ctrl,
     CapsLock,
+    Shift,
+    LShift,
+    RShift,
+}
+
+impl Toggle {
+    /// The modifier to tap, if the toggle is one
+    pub fn tap_key(self) -> Option<TapKey> {
+        match self {
+            Toggle::Ctrl => Some(TapKey::Ctrl),
+            Toggle::Shift => Some(TapKey::Shift),
+            Toggle::LShift => Some(TapKey::LShift),
+            Toggle::RShift => Some(TapKey::RShift),
+            Toggle::Eisu | Toggle::CapsLock => None,
+        }
+    }
+}
+
+#[test]
+fn test_candidates() {
+    let candidates = |text: &str| toml::from_str::<Candidates>(text).unwrap();
+    let lookup = Lookup {
+        prefix: '`',
+        schema: "emoji".to_string(),
+    };
+    let puncts = HashSet::from(['(', ')', '[', ']', '{', '}', '^', '*', '.', ':', '-', '"']);
+    let default = Candidates::default();
+    assert!(default.validate(Some(&lookup), &puncts).is_ok());
+    assert_eq!(default.select('3'), Some(2));
+    assert_eq!(default.select('6'), None);
+    assert_eq!(default.label(0), "1");
+    // home row keys are held with a modifier
+    let home_row = candidates("keys = 'asdfg'\nmodifier = 'Alt'");
+    assert!(home_row.validate(Some(&lookup), &puncts).is_ok());
+    assert_eq!(home_row.select('a'), None);
+    assert_eq!(home_row.select_held(Modifier::Alt, 'D'), Some(2));
+    assert_eq!(home_row.select_held(Modifier::Ctrl, 'D'), None);
+    // or they'd be spelled
+    let valid = |text: &str| candidates(text).validate(Some(&lookup), &puncts).is_ok();
+    assert!(!valid("keys = 'asdfg'"));
+    assert!(!valid("keys = '123`5'"));
+    assert!(!valid("keys = '12325'"));
+    assert!(!valid("keys = '1234^'"));
+    // and the punctuators of the schemas
+    assert!(!valid("keys = '1234['"));
+    assert!(!valid("keys = '1234\"'"));
+    assert!(valid("keys = '1234='"));
+    let nine = candidates("page_size = 9\nkeys = '123456789'\nlabels = ['a']");
+    assert!(nine.validate(None, &puncts).is_err());
+    assert!(valid("page_size = 9\nkeys = '123456789'"));
+    assert!(!valid("page_size = 10\nkeys = '1234567890'"));
 }
 
 #[test]
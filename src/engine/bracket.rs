@@ -0,0 +1,105 @@
+use super::{
+    Engine,
+    output::{
+        COLON, END_OF_CARTOUCHE, END_OF_LONG_GLYPH, END_OF_REVERSE_LONG_GLYPH, MIDDLE_DOT,
+        START_OF_CARTOUCHE, START_OF_LONG_GLYPH, START_OF_REVERSE_LONG_GLYPH,
+    },
+};
+
+/// Cartouches and long glyphs
+const PAIRS: [(char, char); 3] = [
+    (START_OF_CARTOUCHE, END_OF_CARTOUCHE),
+    (START_OF_LONG_GLYPH, END_OF_LONG_GLYPH),
+    (START_OF_REVERSE_LONG_GLYPH, END_OF_REVERSE_LONG_GLYPH),
+];
+
+/// Keeps track of the brackets in a paragraph.
+#[derive(Default)]
+struct Brackets {
+    /// The closers expected, the innermost last
+    open: Vec<char>,
+    /// Char indices of the closers without openers
+    unbalanced: Vec<usize>,
+    len: usize,
+}
+
+impl Brackets {
+    fn scan(&mut self, text: &str) {
+        for ch in text.chars() {
+            if ch == '\n' || ch == '\r' {
+                self.open.clear();
+            } else if let Some(close) = closer(ch) {
+                self.open.push(close);
+            } else if PAIRS.iter().any(|(_, close)| *close == ch) {
+                if self.open.last() == Some(&ch) {
+                    self.open.pop();
+                } else {
+                    self.unbalanced.push(self.len);
+                }
+            }
+            self.len += 1;
+        }
+    }
+}
+
+fn closer(opener: char) -> Option<char> {
+    PAIRS
+        .iter()
+        .find(|(open, _)| *open == opener)
+        .map(|(_, close)| *close)
+}
+
+impl Engine {
+    /// The closers of the brackets left open in the paragraph, the innermost first.
+    pub fn closers(&self, preceding: &str) -> String {
+        let mut brackets = Brackets::default();
+        brackets.scan(preceding);
+        brackets.open.iter().rev().collect()
+    }
+
+    /// Whether the text has closers that don't match any opener before them.
+    pub fn unbalanced(&self, preceding: &str, text: &str) -> bool {
+        let mut brackets = Brackets::default();
+        brackets.scan(preceding);
+        brackets.unbalanced.clear();
+        brackets.scan(text);
+        !brackets.unbalanced.is_empty()
+    }
+
+    /// The closer of the bracket if it's an opener.
+    pub fn closer(&self, opener: char) -> Option<char> {
+        closer(opener)
+    }
+
+    pub fn is_closer(&self, ch: char) -> bool {
+        PAIRS.iter().any(|(_, close)| *close == ch)
+    }
+
+    /// Whether brackets are closed before the punctuator.
+    pub fn ends_sentence(&self, punct: char) -> bool {
+        matches!(punct, MIDDLE_DOT | COLON)
+    }
+}
+
+#[test]
+fn test_brackets() {
+    let engine = Engine::default();
+    // [ ale ( lili
+    assert_eq!(
+        engine.closers("\u{F1990}\u{F1904}\u{F1997}\u{F1928}"),
+        "\u{F1998}\u{F1991}"
+    );
+    assert_eq!(engine.closers("\u{F1990}\u{F1904}\u{F1991}"), "");
+    // ale [ jan: the opener in what's committed along with the preceding text
+    let (preceding, selected) = ("\u{F1904}", "\u{F1990}\u{F1911}");
+    assert_eq!(
+        engine.closers(&format!("{preceding}{selected}")),
+        "\u{F1991}"
+    );
+    assert_eq!(engine.closers("\u{F1990}\u{F1904}\n\u{F1904}"), "");
+    assert!(engine.unbalanced("", "\u{F1904}\u{F1991}"));
+    assert!(!engine.unbalanced("\u{F1990}", "\u{F1904}\u{F1991}"));
+    assert!(engine.unbalanced("\u{F1990}", "\u{F1904}\u{F1998}"));
+    // the closers before the text don't count
+    assert!(!engine.unbalanced("\u{F1991}", "\u{F1904}"));
+}
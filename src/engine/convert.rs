@@ -0,0 +1,390 @@
+use std::{fmt::Display, ops::Range};
+
+use super::{
+    Engine, long_glyph,
+    long_glyph::insert_long_glyph,
+    output::PLACEHOLDER,
+    schema::{Candidate::*, Schema},
+};
+use crate::extend::CharExt;
+
+//----------------------------------------------------------------------------
+//
+//  Whole-text conversion. Unlike compositions, the text is spaced sitelen
+//  Lasina, with names, punctuators and line breaks in it.
+//
+//----------------------------------------------------------------------------
+
+/// How names (capitalized words) are converted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Names {
+    /// Keep them in Latin letters
+    #[default]
+    Keep,
+    /// Spell them in cartouches, a glyph for each letter
+    Cartouche,
+}
+
+/// The converted text along with the words left as they are
+#[derive(Debug, Default)]
+pub struct Conversion {
+    pub output: String,
+    pub unconverted: Vec<Unconverted>,
+}
+
+/// A word that could not be converted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unconverted {
+    pub word: String,
+    /// Line number, starting from 1
+    pub line: usize,
+    /// Column in chars, starting from 1
+    pub column: usize,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// A name that is kept as it is, or can't be spelled in a cartouche
+    Name,
+    /// A word the schema doesn't know
+    Unknown,
+}
+
+impl Display for Unconverted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            Reason::Name => "name",
+            Reason::Unknown => "unknown word",
+        };
+        write!(f, "{}:{}: {reason} '{}'", self.line, self.column, self.word)
+    }
+}
+
+enum Token<'a> {
+    Word(&'a str),
+    Space(&'a str),
+    Punct(char),
+    /// Text kept as it is
+    Kept(&'a str),
+}
+
+/// Split the text into words, whitespaces and punctuators, along with their byte offsets.
+/// The `kept` ranges are taken as they are.
+fn tokenize<'a>(text: &'a str, kept: &[Range<usize>]) -> Vec<(usize, Token<'a>)> {
+    let mut tokens = Vec::new();
+    let mut kept = kept.iter().filter(|range| !range.is_empty()).peekable();
+    let mut pos = 0;
+    while pos < text.len() {
+        if let Some(range) = kept.next_if(|range| range.start <= pos) {
+            tokens.push((range.start, Token::Kept(&text[range.clone()])));
+            pos = pos.max(range.end);
+            continue;
+        }
+        // up to where the text is kept next
+        let end = kept.peek().map_or(text.len(), |range| range.start);
+        let rest = &text[pos..end];
+        let ch = rest.chars().next().unwrap();
+        let offset = pos;
+        let len = if ch.is_alphabetic() {
+            let len = rest
+                .find(|ch: char| !ch.is_alphabetic())
+                .unwrap_or(rest.len());
+            tokens.push((offset, Token::Word(&rest[..len])));
+            len
+        } else if ch.is_whitespace() {
+            let len = rest
+                .find(|ch: char| !ch.is_whitespace())
+                .unwrap_or(rest.len());
+            tokens.push((offset, Token::Space(&rest[..len])));
+            len
+        } else {
+            tokens.push((offset, Token::Punct(ch)));
+            ch.len_utf8()
+        };
+        pos += len;
+    }
+    tokens
+}
+
+/// The line and column of the byte offset, both starting from 1.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// The glyph(s) of the word if the schema has it.
+fn word(schema: &Schema, word: &str) -> Option<String> {
+    match schema.candis.get(word)? {
+        Exact(word, _) => Some(word.clone()),
+        _ => None,
+    }
+}
+
+/// Spell the name in a cartouche with the first glyph starting with each letter.
+fn cartouche(schema: &Schema, name: &str) -> Option<String> {
+    let open = schema.puncts.get(&'[').copied()?;
+    let close = schema.puncts.get(&']').copied()?;
+    let mut output = String::new();
+    output.push(open);
+    let mut buf = [0; 4];
+    for ch in name.to_lowercase().chars() {
+        let glyph = match schema.candis.get(&*ch.encode_utf8(&mut buf))? {
+            Exact(glyph, _) | Unique(glyph) => glyph,
+            Duplicates(glyphs) => glyphs.first()?,
+        };
+        output.push_str(glyph);
+    }
+    output.push(close);
+    Some(output)
+}
+
+/// Whether the char is written in the script of the schema rather than in Latin letters, being
+/// a UCSUR code point or found in the glyphs and punctuators of the schema
+fn is_converted(schema: &Schema, ch: char) -> bool {
+    !ch.is_ascii()
+        && (matches!(ch, '\u{F1900}'..='\u{F19FF}')
+            || schema.puncts.values().any(|punct| *punct == ch)
+            || schema.spellings.keys().any(|word| word.contains(ch)))
+}
+
+impl Engine {
+    /// Convert spaced sitelen Lasina text into the active schema, keeping names and unknown words
+    /// as they are.
+    pub fn transliterate(&self, text: &str) -> String {
+        self.convert(text, Names::Keep).output
+    }
+
+    /// Convert spaced sitelen Lasina text into the active schema. Punctuators are re-mapped,
+    /// quotes and cartouches are paired up and long glyphs are inserted sentence by sentence.
+    pub fn convert(&self, text: &str, names: Names) -> Conversion {
+        self.convert_keeping(text, &[], names)
+    }
+
+    /// Convert the text like `convert`, keeping the byte ranges `kept` (markup for example) as
+    /// they are. Sentences and quotes go on across them, unless they break lines.
+    pub fn convert_keeping(&self, text: &str, kept: &[Range<usize>], names: Names) -> Conversion {
+        let schema = self.schema();
+        let mut conversion = Conversion::default();
+        let mut output = String::with_capacity(text.len() * 2);
+        // where the current sentence starts in the output
+        let mut sentence = 0;
+        let mut first_word = true;
+        let mut spaces = "";
+        let tokens = tokenize(text, kept);
+        for (i, (offset, token)) in tokens.iter().enumerate() {
+            let piece = match *token {
+                // held in place by a placeholder until the output is rendered
+                Token::Kept(kept) => {
+                    let breaking = kept.contains(['\n', '\r']);
+                    if breaking {
+                        self.end_sentence(&mut output, sentence);
+                    }
+                    output.push_str(spaces);
+                    output.push(PLACEHOLDER);
+                    spaces = "";
+                    if breaking {
+                        sentence = output.len();
+                        first_word = true;
+                    }
+                    continue;
+                }
+                Token::Space(space) => {
+                    if space.contains(['\n', '\r']) {
+                        self.end_sentence(&mut output, sentence);
+                        output.push_str(space);
+                        sentence = output.len();
+                        first_word = true;
+                        spaces = "";
+                    } else {
+                        spaces = space;
+                    }
+                    continue;
+                }
+                Token::Word(latin) => {
+                    let lower = latin.to_lowercase();
+                    let is_name = lower != latin;
+                    let converted = match word(schema, &lower) {
+                        // sentences may start with a capital letter
+                        Some(glyph) if !is_name || first_word => Some(glyph),
+                        _ if is_name && names == Names::Cartouche => cartouche(schema, latin),
+                        _ => None,
+                    };
+                    first_word = false;
+                    converted.unwrap_or_else(|| {
+                        let (line, column) = position(text, *offset);
+                        conversion.unconverted.push(Unconverted {
+                            word: latin.to_string(),
+                            line,
+                            column,
+                            reason: match is_name {
+                                true => Reason::Name,
+                                false => Reason::Unknown,
+                            },
+                        });
+                        latin.to_string()
+                    })
+                }
+                Token::Punct(punct) => {
+                    // joiners only join the words right next to them
+                    let joining =
+                        spaces.is_empty() && matches!(tokens.get(i + 1), Some((_, Token::Word(_))));
+                    self.convert_punct(schema, punct, &output, sentence, joining)
+                        .to_string()
+                }
+            };
+            // words in the script are not spaced, unless the schema spaces them
+            let last = output.chars().last();
+            let first = piece.chars().next();
+            if schema.separator.is_some()
+                || !(last.is_some_and(|ch| is_converted(schema, ch))
+                    && first.is_some_and(|ch| is_converted(schema, ch)))
+            {
+                output.push_str(spaces);
+            }
+            spaces = "";
+            output.push_str(&piece);
+            if piece
+                .chars()
+                .last()
+                .is_some_and(|ch| self.ends_sentence(ch) || matches!(ch, '!' | '?'))
+            {
+                self.end_sentence(&mut output, sentence);
+                sentence = output.len();
+                first_word = true;
+            }
+        }
+        output.push_str(spaces);
+        self.end_sentence(&mut output, sentence);
+        let output = self.separate(schema, output, "");
+        let output = self.render(schema, output);
+        let mut kept = tokens.iter().filter_map(|(_, token)| match token {
+            Token::Kept(kept) => Some(*kept),
+            _ => None,
+        });
+        conversion.output = String::with_capacity(output.len());
+        for ch in output.chars() {
+            match ch {
+                PLACEHOLDER => conversion.output.push_str(kept.next().unwrap_or_default()),
+                ch => conversion.output.push(ch),
+            }
+        }
+        conversion
+    }
+
+    /// Re-map the punctuator like `remap_punct` does, except that joiners and long glyph controls
+    /// that don't fit are kept as they are.
+    fn convert_punct(
+        &self,
+        schema: &Schema,
+        punct: char,
+        output: &str,
+        sentence: usize,
+        joining: bool,
+    ) -> char {
+        let remapped = self
+            .pair_punct(punct, output)
+            .or_else(|| schema.puncts.get(&punct).copied())
+            .unwrap_or(punct);
+        if remapped.is_joiner() {
+            let joined = joining
+                && output
+                    .chars()
+                    .last()
+                    .is_some_and(|ch| is_converted(schema, ch));
+            return if joined { remapped } else { punct };
+        }
+        if long_glyph::is_control(remapped) {
+            let text = format!("{}{remapped}", &output[sentence..]);
+            let last = text.chars().count() - 1;
+            if long_glyph::misplaced(&text, false).contains(&last) {
+                return punct;
+            }
+        }
+        remapped
+    }
+
+    /// Insert long glyphs into the sentence at the end of the output.
+    fn end_sentence(&self, output: &mut String, sentence: usize) {
+        let mut text = output.split_off(sentence);
+        insert_long_glyph(&mut text, &self.schema().long_glyph);
+        output.push_str(&text);
+    }
+}
+
+#[test]
+fn test_convert() {
+    let engine = Engine::default();
+    let glyphs = |text: &str| super::glyphs(engine.schema(), text);
+    assert_eq!(
+        engine.transliterate("mi pona. sina lape"),
+        glyphs("mi pona . sina lape")
+    );
+    // joiners only join the words right next to them
+    assert_eq!(
+        engine.transliterate("toki-pona - mi"),
+        format!("{} - {}", glyphs("toki - pona"), glyphs("mi"))
+    );
+    // sentences may start with a capital letter
+    assert_eq!(engine.transliterate("Mi moku"), glyphs("mi moku"));
+    // names and unknown words are kept and spaced
+    let conversion = engine.convert("jan Sonja li toki.\nmi wile xyz", Names::Keep);
+    assert_eq!(
+        conversion.output,
+        format!(
+            "{} Sonja {}\n{} xyz",
+            glyphs("jan"),
+            glyphs("li toki ."),
+            glyphs("mi wile")
+        )
+    );
+    assert_eq!(
+        conversion.unconverted,
+        [
+            Unconverted {
+                word: "Sonja".to_string(),
+                line: 1,
+                column: 5,
+                reason: Reason::Name,
+            },
+            Unconverted {
+                word: "xyz".to_string(),
+                line: 2,
+                column: 9,
+                reason: Reason::Unknown,
+            },
+        ]
+    );
+    // or spelled in cartouches
+    let conversion = engine.convert("jan Ana", Names::Cartouche);
+    assert_eq!(conversion.output, glyphs("jan [ a n a ]"));
+    assert!(conversion.unconverted.is_empty());
+    // other scripts and punctuators stay spaced
+    assert_eq!(
+        engine.transliterate("mi — sina"),
+        format!("{} — {}", glyphs("mi"), glyphs("sina"))
+    );
+    assert_eq!(
+        engine.transliterate("jan “toki”"),
+        format!("{} “{}”", glyphs("jan"), glyphs("toki"))
+    );
+    // quotes are paired up, and brackets that don't fit are kept
+    assert_eq!(
+        engine.transliterate("\"toki\" (a)"),
+        format!("{} ({})", glyphs("「 toki 」"), glyphs("a"))
+    );
+    // the kept text doesn't break the sentence or the quotes around it
+    let conversion = engine.convert_keeping("\"toki *pona*\" xyz", &[6..7, 11..12], Names::Keep);
+    assert_eq!(
+        conversion.output,
+        format!(
+            "{} *{}*{} xyz",
+            glyphs("「 toki"),
+            glyphs("pona"),
+            glyphs("」")
+        )
+    );
+    assert_eq!(conversion.unconverted[0].column, 15);
+}
@@ -1,161 +1,550 @@
-use crate::conf;
-
-const ALA: char = '󱤂';
-const AWEN: char = '󱤈';
-const KEN: char = '󱤘';
-const KEPEKEN: char = '󱤙';
-const LON: char = '󱤬';
-const PI: char = '󱥍';
-const TAWA: char = '󱥩';
-const LA: char = '󱤡';
-const KAMA: char = '󱤖';
-
-const START_OF_LONG_GLYGH: char = '󱦗';
-const END_OF_LONG_GLYPH: char = '󱦘';
-const START_OF_REVERSE_LONG_GLYGH: char = '󱦚';
-const END_OF_REVERSE_LONG_GLYPH: char = '󱦛';
-
-pub(super) fn insert_long_glyph(text: &mut String) {
-    let mut output = String::new();
-    let mut open = false;
-    let mut general_question = None;
-    for ch in text.chars() {
-        // insert reverse long glyph for ala and remember the question
-        if ext_as_ala(ch) {
-            let Some(mut prev) = output.pop() else {
-                output.push(ch);
-                continue;
-            };
-            if prev == END_OF_LONG_GLYPH || prev == ALA {
-                output.push(prev);
-                output.push(ch);
-                continue;
-            }
-            // ala only closes long glyphs that just begin to form structure like "ken ala ken"
-            if prev == START_OF_LONG_GLYGH {
-                open = false;
-                prev = output.pop().unwrap();
-            }
-            if open {
-                output.push(prev);
-                output.push(ch);
-            } else {
-                general_question = Some(prev);
-                output.push(START_OF_REVERSE_LONG_GLYGH);
-                output.push(prev);
-                output.push(END_OF_REVERSE_LONG_GLYPH);
-                output.push(ch);
-            }
-        // to see if ch is being asked. if so, insert long glyph
-        } else if general_question.is_some() && ch == general_question.unwrap() {
-            general_question = None;
-            output.push(START_OF_LONG_GLYGH);
-            output.push(ch);
-            output.push(END_OF_LONG_GLYPH);
-        // no question, insert ch then open long glyph if needed
-        } else if ext_left(ch) {
-            // close previous long glyph if needed
-            if open {
-                let prev = output.pop().unwrap();
-                if prev != START_OF_LONG_GLYGH {
-                    output.push(prev);
-                    output.push(END_OF_LONG_GLYPH);
-                }
+use std::collections::HashSet;
+
+use super::{
+    output::{
+        COMBINING_LONG_GLYPH_EXTENSION, END_OF_CARTOUCHE, END_OF_LONG_GLYPH,
+        END_OF_REVERSE_LONG_GLYPH, START_OF_CARTOUCHE, START_OF_LONG_GLYPH,
+        START_OF_REVERSE_LONG_GLYPH,
+    },
+    schema::{Candidate, Schema},
+};
+use crate::{
+    conf::LongGlyph,
+    extend::{CharExt, IterStr},
+};
+
+//----------------------------------------------------------------------------
+//
+//  Long glyphs extend a glyph over the phrase before or after it. The text is
+//  split into words, which are then grouped into phrases by a few rules.
+//
+//----------------------------------------------------------------------------
+
+/// Which words extend into long glyphs, as configured
+#[derive(Debug, Default, Clone)]
+pub struct Settings {
+    pub words: LongGlyph,
+    /// Whether pi extends over the phrase after it
+    pub long_pi: bool,
+    /// Whether the words other than pi extend
+    pub long_glyph: bool,
+}
+
+/// Which glyphs extend over which phrases
+#[derive(Debug, Default)]
+pub struct Rules {
+    /// Glyphs extending over the phrase after them, like pi and prepositions
+    forward: HashSet<char>,
+    /// Glyphs extending over the phrase they end, like kama
+    backward: HashSet<char>,
+    /// Glyphs extending over the clause before them, like la
+    clause: HashSet<char>,
+    /// Glyphs forming questions like "x ala x"
+    question: HashSet<char>,
+    /// Glyphs ending the phrases extended over
+    particles: HashSet<char>,
+}
+
+impl Rules {
+    /// Look up the words in the schema. Those without a glyph of their own are ignored.
+    pub fn new(schema: &Schema, settings: &Settings) -> Rules {
+        let glyphs = |words: &mut dyn Iterator<Item = &str>| -> HashSet<char> {
+            words.filter_map(|word| glyph(schema, word)).collect()
+        };
+        let words = &settings.words;
+        let mut rules = Rules {
+            particles: glyphs(&mut words.particles.iter_str()),
+            ..Default::default()
+        };
+        if settings.long_pi {
+            rules.forward.extend(glyph(schema, "pi"));
+        }
+        if settings.long_glyph {
+            rules.forward.extend(glyphs(&mut words.forward.iter_str()));
+            rules.backward = glyphs(&mut words.backward.iter_str());
+            rules.clause = glyphs(&mut words.clause.iter_str());
+            rules.question = glyphs(&mut words.question.iter_str());
+        }
+        rules
+    }
+}
+
+/// The glyph of the word if it's a single one
+fn glyph(schema: &Schema, word: &str) -> Option<char> {
+    let Some(Candidate::Exact(glyph, _)) = schema.candis.get(word) else {
+        return None;
+    };
+    let mut chars = glyph.chars();
+    chars.next().filter(|_| chars.next().is_none())
+}
+
+enum Token<'a> {
+    /// A glyph along with the glyphs joined to it, or a cartouche
+    Word(&'a str),
+    /// A long glyph control typed manually
+    Control(char),
+    /// Anything else, which long glyphs don't go across
+    Other(char),
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    /// The first word extends over the rest
+    Forward,
+    /// The last word extends over the rest
+    Backward,
+    /// "x ala x"
+    Question,
+}
+
+/// A long glyph over `words[from..to]`
+struct Span {
+    from: usize,
+    to: usize,
+    kind: Kind,
+}
+
+pub(super) fn insert_long_glyph(text: &mut String, rules: &Rules) {
+    let output = parse(text, rules);
+    if output.len() != text.len() {
+        *text = output;
+    }
+}
+
+/// Insert long glyphs into the text by the rules. Phrases with controls typed manually are left
+/// as they are.
+fn parse(text: &str, rules: &Rules) -> String {
+    let mut output = String::with_capacity(text.len() + 16);
+    let mut words = Vec::new();
+    let mut manual = false;
+    for token in tokenize(text) {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Control(control) => {
+                manual = true;
+                flush(&mut words, manual, rules, &mut output);
+                output.push(control);
             }
-            output.push(ch);
-            output.push(START_OF_LONG_GLYGH);
-            open = true;
-        } else if ext_right(ch) {
-            if open {
+            Token::Other(ch) => {
+                flush(&mut words, manual, rules, &mut output);
+                manual = false;
                 output.push(ch);
-                continue;
             }
-            // get the non-underscored part out
-            let mut temp = String::new();
-            loop {
-                let Some(prev) = output.pop() else {
-                    break;
-                };
-                // todo
-                // in theory END_OF_LONG_GLYPH cound suggest the pattern "x ala x"
-                // it needs to be handled separately (the long glyph for x ala x will be canceled)
-                if ext_right(prev) || prev == END_OF_LONG_GLYPH {
-                    output.push(prev);
-                    break;
-                } else if ext_as_ala(prev) {
-                    temp.push(prev);
-                    let (a, b, c) = (output.pop(), output.pop(), output.pop());
-                    match (a, b, c) {
-                        (
-                            Some(END_OF_REVERSE_LONG_GLYPH),
-                            Some(prev),
-                            Some(START_OF_REVERSE_LONG_GLYGH),
-                        ) => temp.push(prev),
-                        _ => {
-                            if let Some(it) = c {
-                                output.push(it)
-                            }
-                            if let Some(it) = b {
-                                output.push(it)
-                            }
-                            if let Some(it) = a {
-                                output.push(it)
-                            }
-                            break;
-                        }
-                    }
-                } else {
-                    temp.push(prev)
-                }
+        }
+    }
+    flush(&mut words, manual, rules, &mut output);
+    output
+}
+
+/// Write out the phrase, extending the glyphs in it unless it's done manually.
+fn flush(words: &mut Vec<&str>, manual: bool, rules: &Rules, output: &mut String) {
+    if manual {
+        words.drain(..).for_each(|word| output.push_str(word));
+        return;
+    }
+    let mut spans = extend(words, rules).into_iter().peekable();
+    let mut i = 0;
+    while i < words.len() {
+        let Some(span) = spans.next_if(|span| span.from == i) else {
+            output.push_str(words[i]);
+            i += 1;
+            continue;
+        };
+        let words = &words[span.from..span.to];
+        match span.kind {
+            Kind::Forward => {
+                output.push_str(words[0]);
+                output.push(START_OF_LONG_GLYPH);
+                words[1..].iter().for_each(|word| output.push_str(word));
+                output.push(END_OF_LONG_GLYPH);
             }
-            if temp.is_empty() {
-                output.push(ch);
-                continue;
+            Kind::Backward => {
+                output.push(START_OF_REVERSE_LONG_GLYPH);
+                let (head, words) = words.split_last().unwrap();
+                words.iter().for_each(|word| output.push_str(word));
+                output.push(END_OF_REVERSE_LONG_GLYPH);
+                output.push_str(head);
             }
-            output.push(START_OF_REVERSE_LONG_GLYGH);
-            loop {
-                let Some(t) = temp.pop() else {
-                    break;
-                };
-                output.push(t);
+            Kind::Question => {
+                output.push(START_OF_REVERSE_LONG_GLYPH);
+                output.push_str(words[0]);
+                output.push(END_OF_REVERSE_LONG_GLYPH);
+                output.push_str(words[1]);
+                output.push(START_OF_LONG_GLYPH);
+                output.push_str(words[2]);
+                output.push(END_OF_LONG_GLYPH);
             }
-            output.push(END_OF_REVERSE_LONG_GLYPH);
-            output.push(ch);
+        }
+        i = span.to;
+    }
+    words.clear();
+}
+
+/// Find the long glyphs in a phrase, which never overlap.
+fn extend(words: &[&str], rules: &Rules) -> Vec<Span> {
+    let len = words.len();
+    // single glyphs only. compounds and cartouches don't extend
+    let glyphs: Vec<Option<char>> = words
+        .iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        })
+        .collect();
+    let is = |set: &HashSet<char>, i: usize| glyphs[i].is_some_and(|glyph| set.contains(&glyph));
+    let is_particle = |i: usize| is(&rules.particles, i);
+    // the ones extending over the phrase they end must end it, and are taken over by the ones
+    // extending over the clause right after them (as in "tenpo kama la")
+    let backward: Vec<bool> = (0..len)
+        .map(|i| {
+            is(&rules.clause, i) || (is(&rules.backward, i) && (i + 1 == len || is_particle(i + 1)))
+        })
+        .collect();
+    let mut spans = Vec::new();
+    let mut taken = vec![false; len];
+    // x ala x
+    let mut i = 0;
+    while i + 2 < len {
+        if is(&rules.question, i + 1) && words[i] == words[i + 2] && glyphs[i].is_some() {
+            spans.push(Span {
+                from: i,
+                to: i + 3,
+                kind: Kind::Question,
+            });
+            taken[i..i + 3].fill(true);
+            i += 3;
         } else {
-            output.push(ch);
+            i += 1;
         }
     }
-    if open {
-        let prev = output.pop().unwrap();
-        if prev != START_OF_LONG_GLYGH {
-            output.push(prev);
-            output.push(END_OF_LONG_GLYPH);
+    // pi and prepositions go on until a particle or another glyph that extends
+    let mut i = 0;
+    while i < len {
+        if taken[i] || !is(&rules.forward, i) {
+            i += 1;
+            continue;
+        }
+        let mut to = i + 1;
+        while to < len && !taken[to] && !is_particle(to) && !is(&rules.forward, to) && !backward[to]
+        {
+            to += 1;
+        }
+        if to > i + 1 {
+            spans.push(Span {
+                from: i,
+                to,
+                kind: Kind::Forward,
+            });
+            taken[i..to].fill(true);
         }
+        i = to;
     }
-    if text.len() != output.len() {
-        text.clear();
-        text.push_str(&output);
+    // then go back until whatever is taken
+    for head in 0..len {
+        if !backward[head] || taken[head] {
+            continue;
+        }
+        let clause = is(&rules.clause, head);
+        let mut from = head;
+        while from > 0
+            && !taken[from - 1]
+            && !backward[from - 1]
+            && (clause || !is_particle(from - 1))
+        {
+            from -= 1;
+        }
+        if from < head {
+            spans.push(Span {
+                from,
+                to: head + 1,
+                kind: Kind::Backward,
+            });
+            taken[from..=head].fill(true);
+        }
     }
+    spans.sort_by_key(|span| span.from);
+    spans
 }
 
-fn ext_as_ala(ch: char) -> bool {
-    ch == ALA && conf::get().behavior.long_glyph
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        let token = match ch {
+            START_OF_CARTOUCHE => {
+                let mut end = text.len();
+                for (i, ch) in chars.by_ref() {
+                    if ch == END_OF_CARTOUCHE {
+                        end = i + ch.len_utf8();
+                        break;
+                    }
+                }
+                Token::Word(&text[start..end])
+            }
+            ch if is_glyph(ch) => {
+                // take the joiners and the glyphs they join
+                let mut end = start + ch.len_utf8();
+                let mut joined = false;
+                while let Some((i, ch)) =
+                    chars.next_if(|(_, ch)| ch.is_joiner() || (joined && is_glyph(*ch)))
+                {
+                    joined = ch.is_joiner();
+                    end = i + ch.len_utf8();
+                }
+                Token::Word(&text[start..end])
+            }
+            ch if is_control(ch) => Token::Control(ch),
+            ch => Token::Other(ch),
+        };
+        tokens.push(token);
+    }
+    tokens
 }
 
-fn ext_left(ch: char) -> bool {
-    match ch {
-        PI => conf::get().behavior.long_pi,
-        AWEN | KEN | KEPEKEN | LON | TAWA => conf::get().behavior.long_glyph,
-        _ => false,
+/// Find the manually typed long glyph controls that don't fit where they are, by their char
+/// indices. The ones left open are misplaced only if `complete` is set.
+pub(super) fn misplaced(text: &str, complete: bool) -> Vec<usize> {
+    #[derive(PartialEq, Eq, Clone, Copy)]
+    enum State {
+        Outside,
+        /// Right after a glyph, or another long glyph
+        AfterWord,
+        /// Inside a long glyph, with or without words yet
+        Forward(usize, bool),
+        Backward(usize, bool),
+        /// Right after a reverse long glyph, which expects its glyph
+        Head,
     }
+    use State::*;
+    let mut misplaced = Vec::new();
+    let mut state = Outside;
+    for (i, ch) in text.chars().enumerate() {
+        state = match (state, ch) {
+            (Forward(_, _) | Backward(_, _), ch) if is_glyph(ch) || ch.is_joiner() => match state {
+                Forward(start, _) => Forward(start, true),
+                Backward(start, _) => Backward(start, true),
+                _ => unreachable!(),
+            },
+            (_, ch) if is_glyph(ch) || ch == END_OF_CARTOUCHE => AfterWord,
+            (_, ch) if ch.is_joiner() => state,
+            (AfterWord, START_OF_LONG_GLYPH) => Forward(i, false),
+            (Outside | AfterWord, START_OF_REVERSE_LONG_GLYPH) => Backward(i, false),
+            (Forward(_, true), END_OF_LONG_GLYPH) => AfterWord,
+            (Backward(_, true), END_OF_REVERSE_LONG_GLYPH) => Head,
+            (AfterWord, COMBINING_LONG_GLYPH_EXTENSION) => AfterWord,
+            (_, START_OF_LONG_GLYPH..=END_OF_REVERSE_LONG_GLYPH) => {
+                misplaced.push(i);
+                state
+            }
+            // long glyphs don't go across anything else
+            (Forward(start, _) | Backward(start, _), _) => {
+                misplaced.push(start);
+                Outside
+            }
+            (Head, _) => {
+                misplaced.push(i - 1);
+                Outside
+            }
+            _ => Outside,
+        };
+    }
+    match state {
+        Forward(start, _) | Backward(start, _) if complete => misplaced.push(start),
+        Head if complete => misplaced.push(text.chars().count() - 1),
+        _ => (),
+    }
+    misplaced.sort();
+    misplaced
+}
+
+/// The glyphs right before the caret that the new text may form long glyphs with, namely the
+/// trailing run of glyphs and joiners without any punctuators or control characters.
+pub(super) fn context(preceding: &str) -> &str {
+    let start = preceding
+        .char_indices()
+        .rev()
+        .take_while(|(_, ch)| is_glyph(*ch) || ch.is_joiner())
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(preceding.len());
+    &preceding[start..]
+}
+
+/// Insert long glyphs into `text` as if it followed `context`. Returns how many chars at the end
+/// of the context need to be replaced by `text`, which then starts with their new form.
+pub(super) fn insert_long_glyph_after(context: &str, text: &mut String, rules: &Rules) -> usize {
+    let mut whole = String::with_capacity(context.len() + text.len() + 8);
+    whole.push_str(context);
+    whole.push_str(text);
+    insert_long_glyph(&mut whole, rules);
+    let common: usize = context
+        .chars()
+        .zip(whole.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(ch, _)| ch.len_utf8())
+        .sum();
+    text.clear();
+    text.push_str(&whole[common..]);
+    context[common..].chars().count()
 }
 
-#[allow(unused)]
-fn ext_right(ch: char) -> bool {
-    match ch {
-        // KAMA is disabled for now because i don't want to handle "tenpo kama la"
-        LA => conf::get().behavior.long_glyph,
-        KAMA => false,
-        _ => false,
+pub(super) fn is_control(ch: char) -> bool {
+    matches!(ch, START_OF_LONG_GLYPH..=END_OF_REVERSE_LONG_GLYPH)
+}
+
+fn is_glyph(ch: char) -> bool {
+    matches!(ch, '\u{F1900}'..='\u{F198F}' | '\u{F19A0}'..='\u{F19FF}')
+}
+
+#[test]
+fn test_parse() {
+    use super::output::ligature;
+    use crate::conf::Ligature;
+
+    let schema = Schema::from(crate::SITELEN_DICT);
+    let glyphs = |text: &str| super::glyphs(&schema, text);
+    let settings = |words| Settings {
+        words,
+        long_pi: true,
+        long_glyph: true,
+    };
+    let rules = Rules::new(&schema, &settings(LongGlyph::default()));
+    let cases = [
+        // pi phrases
+        ("jan pi kala lili", "jan pi (kala lili)"),
+        ("jan pi kala lili li pona", "jan pi (kala lili) li pona"),
+        ("kulupu pi", "kulupu pi"),
+        ("jan pi ^ kala", "jan pi^kala"),
+        // prepositions
+        ("mi lon tomo", "mi lon (tomo)"),
+        ("mi tawa tomo pi jan ale", "mi tawa (tomo) pi (jan ale)"),
+        ("mi awen e ni", "mi awen e ni"),
+        ("mi kepeken ilo . sina lape", "mi kepeken (ilo). sina lape"),
+        // la
+        ("tenpo ni la mi lape", "{tenpo ni} la mi lape"),
+        ("sina li pona la mi pona", "{sina li pona} la mi pona"),
+        ("lon tomo la mi lape", "lon (tomo) la mi lape"),
+        ("la mi lape", "la mi lape"),
+        // x ala x
+        ("ken ala ken", "{ken} ala (ken)"),
+        ("sina wile ala wile moku", "sina {wile} ala (wile) moku"),
+        ("mi wile ala", "mi wile ala"),
+        (
+            "ona li pona ala pona la mi pona",
+            "ona li {pona} ala (pona) la mi pona",
+        ),
+        // kama
+        ("tenpo kama", "{tenpo} kama"),
+        ("tenpo kama la mi lape", "{tenpo kama} la mi lape"),
+        ("jan kama li pona", "{jan} kama li pona"),
+        ("mi kama sona", "mi kama sona"),
+        ("ona li kama", "ona li kama"),
+        // manual controls
+        ("pi ( kala ) lili", "pi (kala) lili"),
+        ("{ kala } la mi pi kala", "{kala} la mi pi kala"),
+        (
+            "[ ale ] pi kala . mi pi kala",
+            "[ale] pi (kala). mi pi (kala)",
+        ),
+    ];
+    for (input, expected) in cases {
+        let output = parse(&glyphs(input), &rules);
+        let output = ligature(&schema, &output, &Ligature::default());
+        assert_eq!(output.trim_end(), expected, "{input}");
     }
+    // nothing changes without rules
+    let text = glyphs("tenpo ni la mi tawa tomo pi jan ale");
+    assert_eq!(parse(&text, &Rules::default()), text);
+    // custom words, including those of other schemas
+    let words = LongGlyph {
+        forward: vec!["tan".to_string()],
+        clause: vec!["la".to_string()],
+        ..Default::default()
+    };
+    let rules = Rules::new(&schema, &settings(words.clone()));
+    let text = glyphs("mi kama tan ma kasi");
+    let output = ligature(&schema, &parse(&text, &rules), &Ligature::default());
+    assert_eq!(output.trim_end(), "mi kama tan (ma kasi)");
+    let schema = Schema::from("x 󱤀\nyy 󱤂\nz 󱤄\nlo 󱤡");
+    let rules = Rules::new(&schema, &settings(words.clone()));
+    assert_eq!(parse("󱤀󱤂󱤄", &rules), "󱤀󱤂󱤄");
+    let rules = Rules::new(
+        &schema,
+        &settings(LongGlyph {
+            clause: vec!["lo".to_string()],
+            ..words.clone()
+        }),
+    );
+    assert_eq!(parse("󱤀󱤂󱤡󱤄", &rules), "󱦚󱤀󱤂󱦛󱤡󱤄");
+    // and the particles ending the phrases
+    let forward = LongGlyph {
+        forward: vec!["x".to_string()],
+        ..words
+    };
+    let rules = Rules::new(&schema, &settings(forward.clone()));
+    assert_eq!(parse("󱤀󱤂󱤄󱤂", &rules), "󱤀󱦗󱤂󱤄󱤂󱦘");
+    let rules = Rules::new(
+        &schema,
+        &settings(LongGlyph {
+            particles: vec!["z".to_string()],
+            ..forward
+        }),
+    );
+    assert_eq!(parse("󱤀󱤂󱤄󱤂", &rules), "󱤀󱦗󱤂󱦘󱤄󱤂");
+}
+
+#[test]
+fn test_misplaced() {
+    let assert = |text: &str, complete, expected: &[usize]| {
+        let text: String = text
+            .chars()
+            .map(|ch| match ch {
+                'x' => '\u{F1900}',
+                '(' => START_OF_LONG_GLYPH,
+                ')' => END_OF_LONG_GLYPH,
+                '{' => START_OF_REVERSE_LONG_GLYPH,
+                '}' => END_OF_REVERSE_LONG_GLYPH,
+                '~' => COMBINING_LONG_GLYPH_EXTENSION,
+                ch => ch,
+            })
+            .collect();
+        assert_eq!(misplaced(&text, complete), expected, "{text}");
+    };
+    assert("x(xx)x", true, &[]);
+    assert("{x}x(x)~~", true, &[]);
+    assert("x(x", false, &[]);
+    assert("x(x", true, &[1]);
+    assert("(x)", true, &[0, 2]);
+    assert("x()", true, &[1, 2]);
+    assert("x(x(x))", true, &[3, 6]);
+    assert("x(x.x)", true, &[1, 5]);
+    assert("{x}.", true, &[2]);
+    assert("x}", false, &[1]);
+}
+
+#[test]
+fn test_context() {
+    assert_eq!(context("toki 󱥍"), "󱥍");
+    assert_eq!(context("󱤴󱦜󱥍󱦕󱤑"), "󱥍󱦕󱤑");
+    assert_eq!(context("󱥍󱦗󱤑󱦘"), "");
+    assert_eq!(context(""), "");
+    let schema = Schema::from(crate::SITELEN_DICT);
+    let settings = Settings {
+        words: LongGlyph::default(),
+        long_pi: true,
+        long_glyph: true,
+    };
+    let rules = Rules::new(&schema, &settings);
+    let assert = |context: &str, text: &str, expected: &str, replaced: usize| {
+        let mut text = text.to_string();
+        assert_eq!(
+            insert_long_glyph_after(context, &mut text, &rules),
+            replaced
+        );
+        assert_eq!(text, expected);
+    };
+    // mi + jan, pi + jan, ken + ala ken
+    assert("󱤴", "󱤑", "󱤑", 0);
+    assert("󱥍", "󱤑", "󱦗󱤑󱦘", 0);
+    assert("󱤘", "󱤂󱤘", "󱦚󱤘󱦛󱤂󱦗󱤘󱦘", 1);
 }
@@ -1,20 +1,42 @@
+mod bracket;
+mod convert;
 mod long_glyph;
+mod output;
+mod reverse;
 mod schema;
 mod sentence;
 use std::{
     collections::{HashSet, VecDeque},
-    env, fs, iter,
-    path::PathBuf,
+    env, fs, iter, mem,
+    path::{Path, PathBuf},
 };
 
-use self::schema::{Candidate::*, Schema};
-use crate::{CANDI_NUM, EMOJI_DICT, Result, SITELEN_DICT, conf, extend::IterStr, global::IME_NAME};
+pub use self::{
+    convert::{Conversion, Names, Reason, Unconverted},
+    reverse::{Controls, Respelling},
+};
+use self::{
+    long_glyph::insert_long_glyph_after,
+    output::{END_OF_CARTOUCHE, START_OF_CARTOUCHE},
+    schema::{Candidate::*, Schema},
+};
+use crate::{
+    EMOJI_DICT, Result, SITELEN_DICT,
+    conf::{self, Mixed},
+    extend::{CharExt, IterStr, ResultExt},
+    global::IME_NAME,
+};
 
 /// Suggestions from engine
 #[derive(Default, Clone)]
 pub struct Suggestion {
     pub output: String,
     pub groupping: Vec<usize>,
+    /// Name of the schema the suggestion comes from
+    pub schema: String,
+    /// How many chars before the composition are replaced by the output, to form long glyphs
+    /// with them
+    pub replaced: usize,
 }
 
 /// Engine. A struct to store and query words and punctuators
@@ -22,44 +44,92 @@ pub struct Engine {
     schemas: VecDeque<Schema>,
     squote_open: bool,
     dquote_open: bool,
+    /// Name of the schema used for the current composition only
+    lookup: Option<String>,
+    /// Keep sentence punctuators in the composition instead of committing at them
+    multiple_sentences: bool,
 }
 
 impl Default for Engine {
     fn default() -> Engine {
         Engine {
-            schemas: VecDeque::from([Schema::from(SITELEN_DICT), Schema::from(EMOJI_DICT)]),
+            schemas: builtin_schemas(&long_glyph_settings()),
             squote_open: false,
             dquote_open: false,
+            lookup: None,
+            multiple_sentences: conf::get().behavior.multiple_sentences,
         }
     }
 }
 
-impl Engine {
-    pub fn build() -> Result<Engine> {
-        let mut schemas = VecDeque::new();
-        let mut default_schema = None;
-        let path = PathBuf::from(env::var("APPDATA")?)
-            .join(IME_NAME)
-            .join("dict");
-        fs::create_dir_all(&path)?;
-        for entry in fs::read_dir(&path)? {
-            let entry = entry?;
-            let path = entry.path();
-            let file_name = entry.file_name();
-            let file_name = file_name.to_string_lossy();
-            if path.is_dir() || !file_name.ends_with(".dict") {
-                continue;
-            }
-            let schema = Schema::from(fs::read_to_string(path)?.as_str());
-            if file_name == "sitelen.dict" {
-                default_schema = Some(schema)
-            } else {
-                schemas.push_back(schema);
-            }
+/// The schemas that come with the input method.
+fn builtin_schemas(long_glyph: &long_glyph::Settings) -> VecDeque<Schema> {
+    VecDeque::from([
+        Schema::load("sitelen", &schema::read_builtin, long_glyph).unwrap(),
+        Schema::load("emoji", &schema::read_builtin, long_glyph).unwrap(),
+    ])
+}
+
+/// Load the schemas in the dictionary folder, the default one first.
+fn load_schemas(path: &Path, long_glyph: &long_glyph::Settings) -> Result<VecDeque<Schema>> {
+    let mut schemas = VecDeque::new();
+    let mut default_schema = None;
+    // included dictionaries are looked up in the same folder, then among the builtin ones
+    let read = |name: &str| -> Result<String> {
+        let file = path.join(name);
+        if file.exists() {
+            Ok(fs::read_to_string(file)?)
+        } else {
+            schema::read_builtin(name)
         }
-        if let Some(default_schema) = default_schema {
-            schemas.push_front(default_schema);
+    };
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if path.is_dir() || !file_name.ends_with(".dict") {
+            continue;
         }
+        let Ok(schema) = Schema::load(&file_name, &read, long_glyph).log_err() else {
+            continue;
+        };
+        if file_name == "sitelen.dict" {
+            default_schema = Some(schema)
+        } else {
+            schemas.push_back(schema);
+        }
+    }
+    if let Some(default_schema) = default_schema {
+        schemas.push_front(default_schema);
+    }
+    Ok(schemas)
+}
+
+/// The punctuators typed in the schemas, along with the quotes. Read without the configuration,
+/// so that it can be validated against them.
+pub fn puncts() -> Result<HashSet<char>> {
+    let long_glyph = long_glyph::Settings::default();
+    let mut schemas = load_schemas(&dict_path()?, &long_glyph)?;
+    if schemas.is_empty() {
+        schemas = builtin_schemas(&long_glyph);
+    }
+    let puncts = schemas.iter().flat_map(|schema| schema.puncts.keys());
+    Ok(puncts.copied().chain(['\'', '"']).collect())
+}
+
+fn dict_path() -> Result<PathBuf> {
+    let path = PathBuf::from(env::var("APPDATA")?)
+        .join(IME_NAME)
+        .join("dict");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+impl Engine {
+    pub fn build() -> Result<Engine> {
+        let path = dict_path()?;
+        let schemas = load_schemas(&path, &long_glyph_settings())?;
         if schemas.is_empty() {
             log::info!("No dictionary found. Creating default ones now.");
             let sitelen_path = path.as_path().join("sitelen.dict");
@@ -72,6 +142,8 @@ impl Engine {
             schemas,
             squote_open: false,
             dquote_open: false,
+            lookup: None,
+            multiple_sentences: conf::get().behavior.multiple_sentences,
         })
     }
 
@@ -86,16 +158,81 @@ impl Engine {
     }
 
     fn schema(&self) -> &Schema {
+        if let Some(name) = self.lookup.as_ref()
+            && let Some(schema) = self.schemas.iter().find(|schema| schema.name == *name)
+        {
+            return schema;
+        }
         self.schemas.front().unwrap()
     }
 
+    /// Use the schema `name` until `end_lookup` is called, leaving the active schema and the
+    /// states of quotes untouched. Returns `false` if there's no such schema.
+    pub fn start_lookup(&mut self, name: &str) -> bool {
+        if self.has_schema(name) {
+            self.lookup = Some(name.to_string());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn has_schema(&self, name: &str) -> bool {
+        self.schemas.iter().any(|schema| schema.name == name)
+    }
+
+    pub fn end_lookup(&mut self) {
+        self.lookup = None;
+    }
+
+    pub fn looking_up(&self) -> bool {
+        self.lookup.is_some()
+    }
+
     pub fn next_schema(&mut self) {
         self.schemas.rotate_left(1);
         self.squote_open = false;
         self.dquote_open = false;
     }
 
-    pub fn remap_punct(&mut self, punct: char) -> char {
+    pub fn previous_schema(&mut self) {
+        self.schemas.rotate_right(1);
+        self.squote_open = false;
+        self.dquote_open = false;
+    }
+
+    pub fn multiple_sentences(&self) -> bool {
+        self.multiple_sentences
+    }
+
+    pub fn toggle_sentences(&mut self) {
+        self.multiple_sentences = !self.multiple_sentences;
+    }
+
+    /// Make the schema `name` the active one. Returns `false` if there's no such schema.
+    pub fn select_schema(&mut self, name: &str) -> bool {
+        let Some(index) = self.schemas.iter().position(|schema| schema.name == name) else {
+            return false;
+        };
+        self.schemas.rotate_left(index);
+        self.squote_open = false;
+        self.dquote_open = false;
+        true
+    }
+
+    /// Re-map the punctuator. Quotes and cartouche brackets are paired up by the text before the
+    /// caret if it's available, or otherwise by the ones typed earlier.
+    pub fn remap_punct(&mut self, punct: char, preceding: Option<&str>) -> char {
+        if let Some(preceding) = preceding
+            && let Some(paired) = self.pair_punct(punct, preceding)
+        {
+            match punct {
+                '\'' => self.squote_open = paired == self.schema().squote.0,
+                '"' => self.dquote_open = paired == self.schema().dquote.0,
+                _ => (),
+            }
+            return paired;
+        }
         match punct {
             '\'' => {
                 let remmaped = match self.squote_open {
@@ -117,21 +254,206 @@ impl Engine {
         }
     }
 
-    pub fn suggest(&self, spelling: &str) -> Vec<Suggestion> {
+    /// Decide whether the quote or the cartouche bracket opens or closes, judging from the text
+    /// before the caret. Typing the opening bracket again closes the cartouche. Returns `None`
+    /// for other punctuators.
+    pub fn pair_punct(&self, punct: char, preceding: &str) -> Option<char> {
+        let schema = self.schema();
+        let (open, close) = match punct {
+            '\'' => schema.squote,
+            '"' => schema.dquote,
+            punct => match schema.puncts.get(&punct).copied()? {
+                START_OF_CARTOUCHE => (START_OF_CARTOUCHE, END_OF_CARTOUCHE),
+                _ => return None,
+            },
+        };
+        match is_open(preceding, open, close) {
+            true => Some(close),
+            false => Some(open),
+        }
+    }
+
+    /// Whether the punctuator ends a sentence or a clause, or quotes one, and can be kept in the
+    /// composition to type several sentences at once. Brackets can't.
+    pub fn is_sentence_punct(&self, punct: char) -> bool {
+        matches!(punct, '.' | ':' | ',' | '!' | '?' | '\'' | '"')
+    }
+
+    /// The character to insert for the punctuator.
+    /// Joiners are kept as they are since they are part of the spelling.
+    pub fn render_punct(&mut self, punct: char, preceding: Option<&str>) -> char {
+        let remapped = self.remap_punct(punct, preceding);
+        if remapped.is_joiner() {
+            return remapped;
+        }
+        // long glyph controls that don't fit are typed as they are
+        if let Some(preceding) = preceding
+            && long_glyph::is_control(remapped)
+        {
+            let text = format!("{preceding}{remapped}");
+            let last = text.chars().count() - 1;
+            if long_glyph::misplaced(&text, false).contains(&last) {
+                log::debug!("Misplaced long glyph control {punct}.");
+                return punct;
+            }
+        }
+        let rendered = self.render(self.schema(), remapped.to_string());
+        let mut rendered = rendered.chars();
+        match (rendered.next(), rendered.next()) {
+            (Some(ch), None | Some(' ')) => ch,
+            _ => remapped,
+        }
+    }
+
+    /// Suggest for the spelling. `preceding` is the text before the composition, which long
+    /// glyphs may extend over. The first page of suggestions is followed by the first one of the
+    /// next page, if there is one.
+    pub fn suggest(&self, spelling: &str, preceding: &str) -> Vec<Suggestion> {
+        let page_size = conf::get().candidates.page_size;
+        self.suggest_up_to(spelling, preceding, page_size + 1)
+    }
+
+    /// Extend the suggestions to `len` of them if there are that many, keeping the ones already
+    /// there in place. Pages after the first one are only looked up when they are turned to.
+    pub fn suggest_more(
+        &self,
+        spelling: &str,
+        preceding: &str,
+        suggs: &mut Vec<Suggestion>,
+        len: usize,
+    ) {
+        if suggs.len() >= len {
+            return;
+        }
+        let mut known: HashSet<(String, Vec<usize>)> = suggs
+            .iter()
+            .map(|sugg| (sugg.output.clone(), sugg.groupping.clone()))
+            .collect();
+        for sugg in self.suggest_up_to(spelling, preceding, len) {
+            if suggs.len() >= len {
+                break;
+            }
+            if known.insert((sugg.output.clone(), sugg.groupping.clone())) {
+                suggs.push(sugg);
+            }
+        }
+    }
+
+    /// Suggest for the spelling, ranked, up to `limit` of them.
+    fn suggest_up_to(&self, spelling: &str, preceding: &str, limit: usize) -> Vec<Suggestion> {
         if !spelling.is_ascii() {
             return Vec::new();
         }
-        let mut suggs = Vec::with_capacity(CANDI_NUM);
+        // several sentences go first, followed by the words of the first one
+        if self.multiple_sentences
+            && let Some(end) = spelling.find(|ch| self.is_sentence_punct(ch))
+        {
+            let mut suggs = Vec::with_capacity(limit);
+            if let Some(sugg) = self.suggest_sentences_in_block(spelling, preceding) {
+                suggs.push(sugg);
+            }
+            if end != 0 {
+                let mut rest = self.suggest_up_to(&spelling[..end], preceding, limit);
+                rest.truncate(limit - suggs.len());
+                suggs.append(&mut rest);
+            }
+            self.fit_font(&mut suggs);
+            return suggs;
+        }
+        let mixed = &conf::get().mixed;
+        if !mixed.enabled {
+            let mut suggs = self.suggest_in(self.schema(), spelling, preceding, limit);
+            self.fit_font(&mut suggs);
+            return suggs;
+        }
+        let page_size = conf::get().candidates.page_size;
+        let mut suggs = self.suggest_mixed(spelling, preceding, limit, mixed, page_size);
+        self.fit_font(&mut suggs);
+        suggs
+    }
+
+    /// Suggest from the active schema first, followed by the other schemas taking part. The
+    /// latter take the end of the first page, so that they show up without turning pages.
+    fn suggest_mixed(
+        &self,
+        spelling: &str,
+        preceding: &str,
+        limit: usize,
+        mixed: &Mixed,
+        page_size: usize,
+    ) -> Vec<Suggestion> {
+        let mut extra = Vec::new();
+        for name in mixed.schemas.iter() {
+            if *name == self.schema().name {
+                continue;
+            }
+            let Some(schema) = self.schemas.iter().find(|schema| schema.name == *name) else {
+                continue;
+            };
+            extra.extend(self.suggest_in(schema, spelling, preceding, mixed.candidates));
+        }
+        extra.truncate(page_size.min(limit).saturating_sub(1));
+        let mut suggs = self.suggest_in(self.schema(), spelling, preceding, limit - extra.len());
+        let at = (page_size - extra.len()).min(suggs.len());
+        suggs.splice(at..at, extra);
+        suggs
+    }
+
+    fn suggest_in(
+        &self,
+        schema: &Schema,
+        spelling: &str,
+        preceding: &str,
+        limit: usize,
+    ) -> Vec<Suggestion> {
+        let context = long_glyph::context(preceding);
+        let mut suggs = self.candidates(schema, spelling, context, limit);
+        for sugg in suggs.iter_mut() {
+            let preceding = output::unreplaced(preceding, sugg.replaced);
+            let output = self.separate(schema, mem::take(&mut sugg.output), preceding);
+            sugg.output = self.render(schema, output);
+        }
+        // spell unknown words (names for example) with the rules
+        if suggs.is_empty()
+            && limit != 0
+            && let Some(output) = schema.transliterate(spelling)
+        {
+            suggs.push(Suggestion {
+                output,
+                groupping: vec![spelling.len()],
+                schema: schema.name.clone(),
+                replaced: 0,
+            });
+        }
+        suggs
+    }
+
+    /// The sentence and words for the spelling in UCSUR, before they are rendered.
+    fn candidates(
+        &self,
+        schema: &Schema,
+        spelling: &str,
+        context: &str,
+        limit: usize,
+    ) -> Vec<Suggestion> {
+        let mut suggs = Vec::with_capacity(limit);
+        if limit == 0 {
+            return suggs;
+        }
         // suggest a sentence
-        if let Some(sugg) = self.suggest_sentence(spelling) {
+        if let Some(sugg) = self.suggest_sentence(schema, spelling, context) {
             suggs.push(sugg);
         }
+        let sentences = suggs.len();
         // suggest single words
-        let mut remains = CANDI_NUM - suggs.len();
+        let mut remains = limit - suggs.len();
+        if remains == 0 {
+            return suggs;
+        }
         let mut exclude: HashSet<&str> = HashSet::new();
         'outer_loop: for to in (1..=spelling.len()).rev() {
             let slice = &spelling[0..to];
-            let words: &mut dyn Iterator<Item = &str> = match self.schema().candis.get(slice) {
+            let words: &mut dyn Iterator<Item = &str> = match schema.candis.get(slice) {
                 Some(Exact(word, words)) => &mut iter::once(word.as_str()).chain(words.iter_str()),
                 Some(Unique(word)) => &mut iter::once(word.as_str()),
                 Some(Duplicates(words)) => &mut words.iter_str(),
@@ -142,7 +464,7 @@ impl Engine {
 
             for word in words {
                 let words: &mut dyn Iterator<Item = &str> =
-                    if let Some(alters) = self.schema().alters.get(word) {
+                    if let Some(alters) = schema.alters.get(word) {
                         &mut iter::once(word).chain(alters.iter_str())
                     } else {
                         &mut iter::once(word)
@@ -156,7 +478,7 @@ impl Engine {
                     let mut output = word.to_string();
                     let bytes = spelling.as_bytes();
                     for byte in bytes.iter().copied().skip(to) {
-                        if let Some(joiner) = self.schema().puncts.get(&char::from(byte)).copied() {
+                        if let Some(joiner) = schema.puncts.get(&char::from(byte)).copied() {
                             output.push(joiner);
                         } else {
                             break;
@@ -165,6 +487,8 @@ impl Engine {
                     suggs.push(Suggestion {
                         output,
                         groupping: vec![to],
+                        schema: schema.name.clone(),
+                        replaced: 0,
                     });
                     remains -= 1;
                     if remains == 0 {
@@ -173,10 +497,138 @@ impl Engine {
                 }
             }
         }
+        // a single word may form a long glyph with the glyphs before it as well
+        if !context.is_empty() {
+            for sugg in suggs.iter_mut().skip(sentences) {
+                sugg.replaced =
+                    insert_long_glyph_after(context, &mut sugg.output, &schema.long_glyph);
+            }
+        }
         suggs
     }
 }
 
+/// The words extending into long glyphs in `conf.toml`
+fn long_glyph_settings() -> long_glyph::Settings {
+    let conf = conf::get();
+    long_glyph::Settings {
+        words: conf.long_glyph.clone(),
+        long_pi: conf.behavior.long_pi,
+        long_glyph: conf.behavior.long_glyph,
+    }
+}
+
+/// Whether `open` is left unclosed in the paragraph before the caret.
+fn is_open(preceding: &str, open: char, close: char) -> bool {
+    let paragraph = preceding.rsplit(['\n', '\r']).next().unwrap_or_default();
+    if open == close {
+        return paragraph.chars().filter(|ch| *ch == open).count() % 2 == 1;
+    }
+    let mut depth = 0;
+    for ch in paragraph.chars().rev() {
+        if ch == close {
+            depth += 1;
+        } else if ch == open {
+            if depth == 0 {
+                return true;
+            }
+            depth -= 1;
+        }
+    }
+    false
+}
+
+/// Spell the glyphs in Latin so that the tests are readable. Words are separated by spaces, and
+/// punctuators are remapped by the schema. Anything else is kept as it is.
+#[cfg(test)]
+fn glyphs(schema: &Schema, text: &str) -> String {
+    text.split(' ')
+        .map(|word| match schema.candis.get(word) {
+            Some(Exact(glyph, _) | Unique(glyph)) => glyph.clone(),
+            Some(Duplicates(glyphs)) => glyphs[0].clone(),
+            None => match schema.puncts.get(&word.chars().next().unwrap()) {
+                Some(punct) => punct.to_string(),
+                None => word.to_string(),
+            },
+        })
+        .collect()
+}
+
+#[test]
+fn test_pair_punct() {
+    let mut engine = Engine::default();
+    let pair = |punct, preceding| engine.pair_punct(punct, preceding);
+    assert_eq!(pair('"', ""), Some('「'));
+    assert_eq!(pair('"', "「󱤴"), Some('」'));
+    assert_eq!(pair('"', "「󱤴」󱤧"), Some('「'));
+    assert_eq!(pair('"', "「󱤴「󱤧」"), Some('」'));
+    // quotes don't go across paragraphs
+    assert_eq!(pair('"', "「󱤴\n󱤧"), Some('「'));
+    assert_eq!(pair('[', "󱤑󱦐󱤄"), Some('\u{F1991}'));
+    assert_eq!(pair('[', "󱤑󱦐󱤄󱦑"), Some('\u{F1990}'));
+    assert_eq!(pair('.', "「"), None);
+    // the typed quotes are forgotten once the text tells otherwise
+    assert_eq!(engine.remap_punct('"', None), '「');
+    assert_eq!(engine.remap_punct('"', Some("")), '「');
+    assert_eq!(engine.remap_punct('"', None), '」');
+}
+
+#[test]
+fn test_suggest_more() {
+    let engine = Engine::default();
+    let page_size = conf::get().candidates.page_size;
+    let mut suggs = engine.suggest("s", "");
+    assert_eq!(suggs.len(), page_size + 1);
+    let first: Vec<_> = suggs.iter().map(|sugg| sugg.output.clone()).collect();
+    engine.suggest_more("s", "", &mut suggs, page_size * 2 + 1);
+    assert_eq!(suggs.len(), page_size * 2 + 1);
+    // the pages already there stay as they are
+    for (sugg, output) in suggs.iter().zip(first) {
+        assert_eq!(sugg.output, output);
+    }
+    let mut outputs: Vec<_> = suggs.iter().map(|sugg| &sugg.output).collect();
+    outputs.sort();
+    outputs.dedup();
+    assert_eq!(outputs.len(), suggs.len());
+    // there are not that many
+    let mut suggs = engine.suggest("kijetesantakalu", "");
+    let len = suggs.len();
+    engine.suggest_more("kijetesantakalu", "", &mut suggs, 100);
+    assert!(suggs.len() < 100 && suggs.len() >= len);
+}
+
+#[test]
+fn test_mixed() {
+    let engine = Engine::default();
+    let mixed = Mixed {
+        enabled: true,
+        schemas: vec!["emoji".to_string()],
+        candidates: 1,
+    };
+    // the candidate mixed in shows up on the first page
+    let suggs = engine.suggest_mixed("soweli", "", 6, &mixed, 5);
+    assert_eq!(suggs.len(), 6);
+    let mixed_in = suggs
+        .iter()
+        .position(|sugg| sugg.schema == "emoji")
+        .unwrap();
+    assert!(mixed_in < 5);
+    // and stays there as more pages are looked up
+    let more = engine.suggest_mixed("soweli", "", 11, &mixed, 5);
+    assert_eq!(more[mixed_in].output, suggs[mixed_in].output);
+}
+
+#[test]
+fn test_katakana() {
+    let engine = Engine::default();
+    let katakana = Schema::load("katakana", &schema::read_builtin, &long_glyph_settings()).unwrap();
+    let suggs = engine.suggest_in(&katakana, "tokipona", "", 1);
+    assert_eq!(suggs[0].output, "トキ ポナ");
+    // no joiner hangs at the end
+    let suggs = engine.suggest_in(&katakana, "mi-", "", 1);
+    assert_eq!(suggs[0].output, "ミ");
+}
+
 #[test]
 fn repl() {
     use std::io::stdin;
@@ -185,7 +637,7 @@ fn repl() {
     loop {
         buf.clear();
         stdin().read_line(&mut buf).unwrap();
-        let suggs = engine.suggest(&buf);
+        let suggs = engine.suggest(&buf, "");
         for sugg in suggs {
             println!("{}", sugg.output);
         }
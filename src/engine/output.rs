@@ -0,0 +1,378 @@
+use super::{Engine, Suggestion, schema::Schema};
+use crate::{
+    conf::{self, CodePoint, Fallback, Ligature, Mode, Profile, Separator, Unsupported},
+    font,
+};
+
+const ZWJ: char = '\u{200D}';
+const STACKING_JOINER: char = '\u{F1995}';
+const SCALING_JOINER: char = '\u{F1996}';
+pub(super) const START_OF_CARTOUCHE: char = '\u{F1990}';
+pub(super) const END_OF_CARTOUCHE: char = '\u{F1991}';
+pub(super) const START_OF_LONG_GLYPH: char = '\u{F1997}';
+pub(super) const END_OF_LONG_GLYPH: char = '\u{F1998}';
+pub(super) const COMBINING_LONG_GLYPH_EXTENSION: char = '\u{F1999}';
+pub(super) const START_OF_REVERSE_LONG_GLYPH: char = '\u{F199A}';
+pub(super) const END_OF_REVERSE_LONG_GLYPH: char = '\u{F199B}';
+pub(super) const MIDDLE_DOT: char = '\u{F199C}';
+pub(super) const COLON: char = '\u{F199D}';
+pub(super) const ZWSP: char = '\u{200B}';
+/// Stands for the text kept as it is while converting, glued to what's around it
+pub(super) const PLACEHOLDER: char = '\u{FFFC}';
+/// Punctuators after which no separator is needed
+const OPENING_PUNCTS: [char; 8] = ['(', '[', '{', '「', '『', '“', '‘', '«'];
+/// Max number of code points a single word (an emoji sequence for example) can take
+pub(super) const MAX_WORD_LEN: usize = 8;
+
+//----------------------------------------------------------------------------
+//
+//  The suggestions are built out of UCSUR code points. Before being shown
+//  and committed, they are converted into what the user's font expects.
+//
+//----------------------------------------------------------------------------
+
+impl Engine {
+    /// Convert the UCSUR text into what will be committed.
+    pub(super) fn render(&self, schema: &Schema, text: String) -> String {
+        let text = match mode(schema) {
+            Mode::Ucsur => text,
+            Mode::Ligature => ligature(schema, &text, &conf::get().ligature),
+        };
+        match profile(schema) {
+            Some(profile) => transcode(&text, profile),
+            None => text,
+        }
+    }
+}
+
+impl Engine {
+    /// Demote or hide the suggestions that the font would render as tofu.
+    pub(super) fn fit_font(&self, suggs: &mut Vec<Suggestion>) {
+        let Some(font) = font::get() else {
+            return;
+        };
+        match conf::get().font.unsupported {
+            Unsupported::Keep => (),
+            Unsupported::Demote => suggs.sort_by_key(|sugg| !font.supports(&sugg.output)),
+            Unsupported::Hide => suggs.retain(|sugg| font.supports(&sugg.output)),
+        }
+    }
+}
+
+impl Engine {
+    /// Insert separators between the words of a suggestion, and before it if it follows a word
+    /// at the end of `preceding`.
+    pub(super) fn separate(&self, schema: &Schema, text: String, preceding: &str) -> String {
+        let separator = conf::get().output.separator;
+        // the schema spaces its words by itself
+        if separator == Separator::None || mode(schema) != Mode::Ucsur || schema.separator.is_some()
+        {
+            return text;
+        }
+        let mut tail = Tail::after(preceding);
+        let mut output = String::with_capacity(text.len() + 16);
+        for ch in text.chars() {
+            if let Some(separator) = tail.push(ch, separator) {
+                output.push(separator);
+            }
+            output.push(ch);
+        }
+        output
+    }
+}
+
+/// The text before the chars that a suggestion replaces to form long glyphs.
+pub(super) fn unreplaced(preceding: &str, replaced: usize) -> &str {
+    let end = preceding
+        .char_indices()
+        .rev()
+        .take(replaced)
+        .last()
+        .map_or(preceding.len(), |(i, _)| i);
+    &preceding[..end]
+}
+
+/// The end of the text so far, as far as separators are concerned
+#[derive(Default, Clone, Copy)]
+struct Tail {
+    /// How many cartouches and long glyphs are open
+    depth: u8,
+    last: Last,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum Last {
+    /// Nothing, a space, a joiner or an opening bracket
+    #[default]
+    Glue,
+    Word,
+    Punct,
+    /// The end of a sentence
+    Stop,
+}
+
+impl Tail {
+    /// The end of the text.
+    fn after(text: &str) -> Tail {
+        let mut tail = Tail::default();
+        for ch in text.chars() {
+            tail.push(ch, Separator::None);
+        }
+        tail
+    }
+
+    /// Move past `ch`, returning the separator to insert before it if any.
+    fn push(&mut self, ch: char, separator: Separator) -> Option<char> {
+        let (separable, last) = match ch {
+            ZWJ | STACKING_JOINER | SCALING_JOINER => (false, Last::Glue),
+            // the long glyph extends the preceding glyph, and the reverse one the following
+            START_OF_LONG_GLYPH => {
+                self.depth = self.depth.saturating_add(1);
+                (false, Last::Glue)
+            }
+            START_OF_CARTOUCHE | START_OF_REVERSE_LONG_GLYPH => {
+                self.depth = self.depth.saturating_add(1);
+                (self.depth == 1, Last::Glue)
+            }
+            END_OF_CARTOUCHE | END_OF_LONG_GLYPH => {
+                self.depth = self.depth.saturating_sub(1);
+                (false, Last::Word)
+            }
+            END_OF_REVERSE_LONG_GLYPH => {
+                self.depth = self.depth.saturating_sub(1);
+                (false, Last::Glue)
+            }
+            // extensions, variation selectors, skin tones, keycaps and tags
+            COMBINING_LONG_GLYPH_EXTENSION
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+            | '\u{20E3}'
+            | '\u{E0020}'..='\u{E007F}' => (false, self.last),
+            MIDDLE_DOT | '.' | '!' | '?' | '。' | '！' | '？' => (false, Last::Stop),
+            ch if ch.is_whitespace() || OPENING_PUNCTS.contains(&ch) || ch == PLACEHOLDER => {
+                (false, Last::Glue)
+            }
+            COLON | '\u{2000}'..='\u{206F}' | '\u{3000}'..='\u{303F}' | '\u{FF00}'..='\u{FF65}' => {
+                (false, Last::Punct)
+            }
+            ch if ch.is_ascii_punctuation() => (false, Last::Punct),
+            _ => (self.depth == 0, Last::Word),
+        };
+        let prev = self.last;
+        self.last = last;
+        if !separable {
+            return None;
+        }
+        match (separator, prev) {
+            (Separator::Zwsp, Last::Word | Last::Punct | Last::Stop) => Some(ZWSP),
+            (Separator::Space, Last::Word | Last::Punct | Last::Stop) => Some(' '),
+            (Separator::Sentence, Last::Stop) => Some(' '),
+            _ => None,
+        }
+    }
+}
+
+fn mode(schema: &Schema) -> Mode {
+    let conf = conf::get();
+    conf.schema
+        .get(&schema.name)
+        .and_then(|schema| schema.mode)
+        .unwrap_or(conf.output.mode)
+}
+
+fn profile(schema: &Schema) -> Option<&'static Profile> {
+    let conf = conf::get();
+    let name = conf
+        .schema
+        .get(&schema.name)
+        .and_then(|schema| schema.profile.as_ref())
+        .or(conf.output.profile.as_ref())?;
+    let profile = conf.profile.get(name);
+    if profile.is_none() {
+        log::warn!("Profile '{name}' is not found.");
+    }
+    profile
+}
+
+/// Map the UCSUR text to the code points the font supports.
+fn transcode(text: &str, profile: &Profile) -> String {
+    let mut output = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if let Some(mapped) = profile.map.get(&CodePoint(ch)) {
+            output.push_str(&mapped.0);
+            continue;
+        }
+        match ch {
+            STACKING_JOINER | SCALING_JOINER => match profile.joiner {
+                Fallback::Keep => output.push(ch),
+                Fallback::Zwj => output.push(ZWJ),
+                Fallback::Drop => (),
+            },
+            START_OF_LONG_GLYPH..=END_OF_REVERSE_LONG_GLYPH if !profile.long_glyph => (),
+            ch => {
+                let shifted = profile.shift.iter().find_map(|(from, to, onto)| {
+                    (from.0..=to.0)
+                        .contains(&ch)
+                        .then(|| char::from_u32(onto.0 as u32 + ch as u32 - from.0 as u32))
+                        .flatten()
+                });
+                output.push(shifted.unwrap_or(ch));
+            }
+        }
+    }
+    output
+}
+
+enum Token<'a> {
+    Word(&'a str),
+    Joiner(&'a str),
+    Open(&'a str),
+    Close(&'a str),
+    Punct(char),
+}
+
+/// Spell the glyphs in Latin words for fonts that render them as ligatures.
+pub(super) fn ligature(schema: &Schema, text: &str, syntax: &Ligature) -> String {
+    use Token::*;
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    'outer: while let Some(ch) = rest.chars().next() {
+        // find the longest word the glyph(s) stand for
+        let ends = rest
+            .char_indices()
+            .map(|(i, _)| i)
+            .skip(1)
+            .chain([rest.len()]);
+        let ends: Vec<usize> = ends.take(MAX_WORD_LEN).collect();
+        for end in ends.into_iter().rev() {
+            if let Some(spelling) = schema.spellings.get(&rest[..end]) {
+                tokens.push(Word(spelling));
+                rest = &rest[end..];
+                continue 'outer;
+            }
+        }
+        rest = &rest[ch.len_utf8()..];
+        tokens.push(match ch {
+            ZWJ => Joiner(&syntax.zwj),
+            STACKING_JOINER => Joiner(&syntax.stack),
+            SCALING_JOINER => Joiner(&syntax.scale),
+            START_OF_CARTOUCHE => Open(&syntax.cartouche.0),
+            END_OF_CARTOUCHE => Close(&syntax.cartouche.1),
+            START_OF_LONG_GLYPH => Open(&syntax.long_glyph.0),
+            END_OF_LONG_GLYPH => Close(&syntax.long_glyph.1),
+            START_OF_REVERSE_LONG_GLYPH => Open(&syntax.reverse_long_glyph.0),
+            END_OF_REVERSE_LONG_GLYPH => Close(&syntax.reverse_long_glyph.1),
+            COMBINING_LONG_GLYPH_EXTENSION => continue,
+            MIDDLE_DOT => Punct('.'),
+            COLON => Punct(':'),
+            punct => Punct(punct),
+        });
+    }
+    // words are separated by spaces, except for those glued by joiners and brackets
+    let mut output = String::with_capacity(text.len() * 4);
+    let mut glued = true;
+    for token in tokens.iter() {
+        match token {
+            Word(text) | Open(text) => {
+                if !glued {
+                    output.push(' ');
+                }
+                output.push_str(text);
+                glued = matches!(token, Open(_));
+            }
+            Joiner(text) => {
+                output.push_str(text);
+                glued = true;
+            }
+            Close(text) => {
+                output.push_str(text);
+                glued = false;
+            }
+            Punct(punct) => {
+                output.push(*punct);
+                glued = false;
+            }
+        }
+    }
+    // leave a space for the next word unless it's meant to be joined
+    if !glued {
+        output.push(' ');
+    }
+    output
+}
+
+#[test]
+fn test_ligature() {
+    let schema = Schema::from(crate::SITELEN_DICT);
+    let syntax = Ligature::default();
+    // spell the glyphs in Latin so that the test is readable
+    let glyphs = |text: &str| super::glyphs(&schema, text);
+    let assert = |text: String, expected: &str| {
+        assert_eq!(ligature(&schema, &text, &syntax), expected);
+    };
+    assert(glyphs("soweli pona"), "soweli pona ");
+    assert(glyphs("pi ( kala lili )"), "pi (kala lili) ");
+    assert(glyphs("{ kala lili } kama"), "{kala lili} kama ");
+    assert(glyphs("kala ^ lili"), "kala^lili ");
+    assert(glyphs("kala -"), "kala-");
+    assert(glyphs("mi li pona ."), "mi li pona. ");
+    assert(glyphs("jan [ sona ijo ]"), "jan [sona ijo] ");
+}
+
+#[test]
+fn test_separate() {
+    let separate = |text: &str, separator| {
+        let mut tail = Tail::default();
+        let mut output = String::new();
+        for ch in text.chars() {
+            output.extend(tail.push(ch, separator));
+            output.push(ch);
+        }
+        output
+    };
+    // mi lon e kiwen. jan [ale] li pi (kili lili) {ale} ala
+    let text = "󱤴󱤬󱤉󱤛󱦜󱤑󱦐󱤄󱦑󱤧󱥍󱦗󱤚󱤨󱦘󱦚󱤄󱦛󱤂";
+    assert_eq!(
+        separate(text, Separator::Zwsp),
+        "󱤴\u{200B}󱤬\u{200B}󱤉\u{200B}󱤛󱦜\u{200B}󱤑\u{200B}󱦐󱤄󱦑\u{200B}󱤧\u{200B}󱥍󱦗󱤚󱤨󱦘\u{200B}󱦚󱤄󱦛󱤂"
+    );
+    assert_eq!(separate(text, Separator::Sentence), "󱤴󱤬󱤉󱤛󱦜 󱤑󱦐󱤄󱦑󱤧󱥍󱦗󱤚󱤨󱦘󱦚󱤄󱦛󱤂");
+    assert_eq!(separate(text, Separator::None), text);
+    // joiners and emoji sequences
+    assert_eq!(separate("󱤑󱦕󱤧󱥍", Separator::Space), "󱤑󱦕󱤧 󱥍");
+    assert_eq!(separate("❤️👩‍👩‍👦", Separator::Space), "❤️ 👩‍👩‍👦");
+    // the text before the caret tells whether to separate from it
+    let tail = |text: &str| {
+        let mut tail = Tail::after(text);
+        tail.push('󱤴', Separator::Space)
+    };
+    assert_eq!(tail("󱤑"), Some(' '));
+    assert_eq!(tail("󱤑 "), None);
+    assert_eq!(tail("󱦐󱤑"), None);
+    assert_eq!(tail(""), None);
+    assert_eq!(unreplaced("󱤴󱤬󱤉", 2), "󱤴");
+    assert_eq!(unreplaced("󱤴", 0), "󱤴");
+}
+
+#[test]
+fn test_transcode() {
+    let profile: Profile = toml::from_str(
+        r#"
+        joiner = "Zwj"
+        long_glyph = false
+        shift = [["U+F1900", "U+F198F", "U+E000"]]
+        map = { "U+F199C" = ".", "U+F1990" = "", "U+F1991" = "U+E0FF U+E0FF" }
+        "#,
+    )
+    .unwrap();
+    let assert = |text: &str, expected: &str| assert_eq!(transcode(text, &profile), expected);
+    assert("\u{F1900}\u{F1988}", "\u{E000}\u{E088}");
+    assert("\u{F1914}\u{F1995}\u{F1928}", "\u{E014}\u{200D}\u{E028}");
+    assert("\u{F194D}\u{F1997}\u{F1914}\u{F1998}", "\u{E04D}\u{E014}");
+    assert(
+        "\u{F1990}\u{F1900}\u{F1991}\u{F199C}",
+        "\u{E000}\u{E0FF}\u{E0FF}.",
+    );
+    assert("🦡", "🦡");
+    assert!(toml::from_str::<Profile>(r#"map = { "U+F1900U" = "" }"#).is_err());
+}
@@ -0,0 +1,268 @@
+use super::{
+    Engine, Suggestion, long_glyph,
+    output::{
+        COLON, COMBINING_LONG_GLYPH_EXTENSION, END_OF_CARTOUCHE, END_OF_LONG_GLYPH,
+        END_OF_REVERSE_LONG_GLYPH, MAX_WORD_LEN, MIDDLE_DOT, START_OF_CARTOUCHE,
+        START_OF_LONG_GLYPH, START_OF_REVERSE_LONG_GLYPH, ZWSP,
+    },
+    schema::Schema,
+};
+use crate::{conf, extend::CharExt};
+
+//----------------------------------------------------------------------------
+//
+//  Sitelen pona back to sitelen Lasina, for those without the font and for
+//  tools that only understand Latin letters.
+//
+//----------------------------------------------------------------------------
+
+/// What becomes of long glyphs and joiners
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Controls {
+    /// Leave them out
+    #[default]
+    Drop,
+    /// Write them as they are typed, like `(` and `-`
+    Annotate,
+}
+
+/// The spelling of the glyphs at the end of a text, for them to be composed again
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Respelling {
+    /// Where the glyphs start in the text
+    pub from: usize,
+    pub spelling: String,
+    /// How the glyphs were grouped, the same way as suggestions are
+    pub groupping: Vec<usize>,
+}
+
+/// The punctuator typed for the char.
+fn typed(schema: &Schema, ch: char) -> Option<char> {
+    schema
+        .puncts
+        .iter()
+        .filter(|(_, remapped)| **remapped == ch)
+        .map(|(punct, _)| *punct)
+        .min()
+}
+
+/// The longest word at the start of the text, along with its length.
+fn word<'a>(schema: &'a Schema, text: &str) -> Option<(&'a str, usize)> {
+    let ends = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .skip(1)
+        .chain([text.len()])
+        .take(MAX_WORD_LEN);
+    let ends: Vec<usize> = ends.collect();
+    ends.into_iter().rev().find_map(|end| {
+        let spelling = schema.spellings.get(&text[..end])?;
+        Some((spelling.as_str(), end))
+    })
+}
+
+impl Engine {
+    /// Convert the sitelen pona text back into spaced Latin words with the active schema.
+    /// Cartouches become capitalized names spelled with the first letter of each glyph.
+    pub fn reverse(&self, text: &str, controls: Controls) -> String {
+        let schema = self.schema();
+        let mut output = String::with_capacity(text.len());
+        // whether the next word goes without a space before it
+        let mut glued = true;
+        let mut name: Option<String> = None;
+        let mut rest = text;
+        while let Some(ch) = rest.chars().next() {
+            if let Some((word, len)) = word(schema, rest) {
+                rest = &rest[len..];
+                match name.as_mut() {
+                    Some(name) => name.extend(word.chars().next()),
+                    None => {
+                        if !glued {
+                            output.push(' ');
+                        }
+                        output.push_str(word);
+                        glued = false;
+                    }
+                }
+                continue;
+            }
+            rest = &rest[ch.len_utf8()..];
+            if let Some(name) = name.as_mut()
+                && ch != END_OF_CARTOUCHE
+            {
+                name.push(ch);
+                continue;
+            }
+            let annotation = match controls {
+                Controls::Drop => None,
+                Controls::Annotate => typed(schema, ch),
+            };
+            match ch {
+                START_OF_CARTOUCHE => name = Some(String::new()),
+                END_OF_CARTOUCHE => {
+                    let name = name.take().unwrap_or_default();
+                    let mut chars = name.chars();
+                    if !glued {
+                        output.push(' ');
+                    }
+                    output.extend(chars.next().map(|ch| ch.to_ascii_uppercase()));
+                    output.extend(chars);
+                    glued = false;
+                }
+                START_OF_LONG_GLYPH | START_OF_REVERSE_LONG_GLYPH => {
+                    if let Some(annotation) = annotation {
+                        if !glued {
+                            output.push(' ');
+                        }
+                        output.push(annotation);
+                        glued = true;
+                    }
+                }
+                END_OF_LONG_GLYPH | END_OF_REVERSE_LONG_GLYPH => output.extend(annotation),
+                COMBINING_LONG_GLYPH_EXTENSION | ZWSP => (),
+                joiner if joiner.is_joiner() => {
+                    if let Some(annotation) = annotation {
+                        output.push(annotation);
+                        glued = true;
+                    }
+                }
+                MIDDLE_DOT => {
+                    output.push('.');
+                    glued = false;
+                }
+                COLON => {
+                    output.push(':');
+                    glued = false;
+                }
+                quote if quote == schema.dquote.0 || quote == schema.squote.0 => {
+                    if !glued {
+                        output.push(' ');
+                    }
+                    output.push(if quote == schema.dquote.0 { '"' } else { '\'' });
+                    glued = true;
+                }
+                quote if quote == schema.dquote.1 || quote == schema.squote.1 => {
+                    output.push(if quote == schema.dquote.1 { '"' } else { '\'' });
+                    glued = false;
+                }
+                space if space.is_whitespace() => {
+                    output.push(space);
+                    glued = true;
+                }
+                other => {
+                    output.push(other);
+                    glued = false;
+                }
+            }
+        }
+        // a cartouche left open
+        if let Some(name) = name {
+            output.push_str(&name);
+        }
+        output
+    }
+}
+
+impl Engine {
+    /// Rebuild the spelling of the glyphs at the end of the text, which may be joined. Long glyphs
+    /// are left out, as they are inserted again by the rules. `None` if there's no glyph there.
+    pub fn respell(&self, text: &str) -> Option<Respelling> {
+        let schema = self.schema();
+        let (from, _) = text
+            .char_indices()
+            .rev()
+            .take_while(|(_, ch)| !ch.is_ascii() && !ch.is_whitespace())
+            .last()?;
+        let mut respelling = Respelling {
+            from,
+            ..Default::default()
+        };
+        let mut joined = false;
+        let mut rest = &text[from..];
+        while let Some(ch) = rest.chars().next() {
+            if let Some((word, len)) = word(schema, rest) {
+                rest = &rest[len..];
+                respelling.spelling.push_str(word);
+                let to = respelling.spelling.len();
+                match respelling.groupping.last_mut() {
+                    Some(last) if joined => *last = to,
+                    _ => respelling.groupping.push(to),
+                }
+                joined = false;
+                continue;
+            }
+            rest = &rest[ch.len_utf8()..];
+            match (ch, typed(schema, ch)) {
+                (joiner, Some(punct)) if joiner.is_joiner() && !respelling.spelling.is_empty() => {
+                    respelling.spelling.push(punct);
+                    *respelling.groupping.last_mut().unwrap() = respelling.spelling.len();
+                    joined = true;
+                }
+                (control, _) if long_glyph::is_control(control) || control == ZWSP => (),
+                // start over after what can't be spelled
+                _ => {
+                    respelling = Respelling {
+                        from: text.len() - rest.len(),
+                        ..Default::default()
+                    };
+                    joined = false;
+                }
+            }
+        }
+        (!respelling.spelling.is_empty()).then_some(respelling)
+    }
+
+    /// Suggestions for the respelled glyphs, led by the glyphs as they were.
+    pub fn suggest_respelled(&self, text: &str, respelling: &Respelling) -> Vec<Suggestion> {
+        let original = &text[respelling.from..];
+        let mut suggs = self.suggest(&respelling.spelling, &text[..respelling.from]);
+        suggs.retain(|sugg| sugg.output != original);
+        suggs.insert(
+            0,
+            Suggestion {
+                output: original.to_string(),
+                groupping: respelling.groupping.clone(),
+                schema: self.schema().name.clone(),
+                replaced: 0,
+            },
+        );
+        suggs.truncate(conf::get().candidates.page_size + 1);
+        suggs
+    }
+}
+
+#[test]
+fn test_reverse() {
+    use super::Names;
+    let engine = Engine::default();
+    let text = "jan Sonja li toki e ni: \"toki-pona li pona\". ona li pona.";
+    let converted = engine.convert(text, Names::Cartouche).output;
+    assert_eq!(
+        engine.reverse(&converted, Controls::Drop),
+        text.replace('-', " ")
+    );
+    assert_eq!(engine.reverse(&converted, Controls::Annotate), text);
+    // long glyphs
+    let converted = engine.transliterate("mi lon (ma tomo) la");
+    assert_eq!(
+        engine.reverse(&converted, Controls::Drop),
+        "mi lon ma tomo la"
+    );
+    assert_eq!(
+        engine.reverse(&converted, Controls::Annotate),
+        "mi lon (ma tomo) la"
+    );
+}
+
+#[test]
+fn test_respell() {
+    let engine = Engine::default();
+    let text = format!("mi 「{}", engine.transliterate("toki-pona li pona"));
+    let respelling = engine.respell(&text).unwrap();
+    assert_eq!(&text[..respelling.from], "mi 「");
+    assert_eq!(respelling.spelling, "toki-ponalipona");
+    assert_eq!(respelling.groupping, [9, 11, 15]);
+    let suggs = engine.suggest_respelled(&text, &respelling);
+    assert_eq!(suggs[0].output, &text[respelling.from..]);
+    assert!(engine.respell("mi ").is_none());
+}
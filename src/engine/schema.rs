@@ -1,8 +1,14 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
 use Candidate::*;
 use log::error;
 
+use super::long_glyph::{Rules, Settings};
+use crate::{EMOJI_DICT, Error, KATAKANA_DICT, Result, SITELEN_DICT, extend::ResultExt};
+
 /// To expain why a certain spelling is mapped to certain word(s)
 #[derive(Debug)]
 pub enum Candidate {
@@ -22,11 +28,121 @@ pub enum Candidate {
 /// The dicitonary is indexed in a special way.
 #[derive(Debug)]
 pub struct Schema {
+    /// The file name of the dictionary without the extension.
+    pub name: String,
     pub candis: HashMap<String, Candidate>,
     pub alters: HashMap<String, Vec<String>>,
+    /// The spelling of every word (and its alternatives)
+    pub spellings: HashMap<String, String>,
     pub puncts: HashMap<char, char>,
     pub squote: (char, char),
     pub dquote: (char, char),
+    /// Rewrite rules of a rule-based schema. Empty for ordinary schemas.
+    pub rules: Vec<Rule>,
+    /// What goes between the words of a sentence, for scripts that space them
+    pub separator: Option<char>,
+    /// The glyphs extending into long glyphs
+    pub long_glyph: Rules,
+}
+
+/// A rewrite rule that spells a syllable in another script.
+/// Written as `@rule {syllable} {output}` in dictionaries, where the syllable can be
+/// prefixed with `^` (start of a word), suffixed with `$` (end of a word),
+/// `[letters]` (followed by one of the letters) or `[!letters]` (not followed by any of them).
+#[derive(Debug)]
+pub struct Rule {
+    syllable: String,
+    output: String,
+    word_start: bool,
+    word_end: bool,
+    follow: Follow,
+}
+
+#[derive(Debug)]
+enum Follow {
+    Any,
+    OneOf(String),
+    NoneOf(String),
+}
+
+impl Rule {
+    fn parse(pattern: &str, output: &str) -> Option<Rule> {
+        let (pattern, word_start) = match pattern.strip_prefix('^') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, follow) = match pattern.find('[') {
+            Some(open) if pattern.ends_with(']') => {
+                let letters = &pattern[open + 1..pattern.len() - 1];
+                let follow = match letters.strip_prefix('!') {
+                    Some(letters) => Follow::NoneOf(letters.to_string()),
+                    None => Follow::OneOf(letters.to_string()),
+                };
+                (&pattern[..open], follow)
+            }
+            _ => (pattern, Follow::Any),
+        };
+        let (pattern, word_end) = match pattern.strip_suffix('$') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Rule {
+            syllable: pattern.to_string(),
+            output: output.to_string(),
+            word_start,
+            word_end,
+            follow,
+        })
+    }
+
+    fn matches(&self, word: &str, pos: usize) -> bool {
+        if (self.word_start && pos != 0) || !word[pos..].starts_with(&self.syllable) {
+            return false;
+        }
+        let end = pos + self.syllable.len();
+        if self.word_end && end != word.len() {
+            return false;
+        }
+        let next = word[end..].chars().next();
+        match &self.follow {
+            Follow::Any => true,
+            Follow::OneOf(letters) => next.is_some_and(|next| letters.contains(next)),
+            Follow::NoneOf(letters) => !next.is_some_and(|next| letters.contains(next)),
+        }
+    }
+}
+
+/// Spell the word with the first matching rule at each position.
+/// Characters that no rule matches are kept as they are.
+fn transliterate(rules: &[Rule], word: &str) -> String {
+    let mut output = String::new();
+    let mut pos = 0;
+    while pos < word.len() {
+        if let Some(rule) = rules.iter().find(|rule| rule.matches(word, pos)) {
+            output.push_str(&rule.output);
+            pos += rule.syllable.len();
+        } else {
+            let ch = word[pos..].chars().next().unwrap();
+            output.push(ch);
+            pos += ch.len_utf8();
+        }
+    }
+    output
+}
+
+impl Schema {
+    /// Spell an arbitrary word (a name for example) with the rules of the schema.
+    /// Returns `None` for ordinary schemas.
+    pub fn transliterate(&self, word: &str) -> Option<String> {
+        if self.rules.is_empty() {
+            None
+        } else {
+            Some(transliterate(&self.rules, &word.to_ascii_lowercase()))
+        }
+    }
 }
 
 //----------------------------------------------------------------------------
@@ -50,11 +166,8 @@ impl<'a> From<&'a str> for Atom<'a> {
         let first_ch = chars.next().unwrap();
         if !first_ch.is_alphanumeric() && chars.next().is_none() {
             Nanch(first_ch)
-        } else if str.starts_with("U+") {
-            match u32::from_str_radix(&str[2..], 16)
-                .ok()
-                .and_then(char::from_u32)
-            {
+        } else if let Some(code) = str.strip_prefix("U+") {
+            match u32::from_str_radix(code, 16).ok().and_then(char::from_u32) {
                 Some(nanch) => Nanch(nanch),
                 None => Text(str),
             }
@@ -74,81 +187,268 @@ impl Display for Atom<'_> {
     }
 }
 
-impl From<&str> for Schema {
-    fn from(value: &str) -> Schema {
+/// Entries, punctuators and quotes collected from a dictionary file (and the files it includes)
+/// before being indexed into a schema.
+#[derive(Default)]
+struct Dict {
+    entries: Vec<(String, Vec<String>)>,
+    puncts: HashMap<char, char>,
+    squote: Option<(char, char)>,
+    dquote: Option<(char, char)>,
+    rules: Vec<Rule>,
+    separator: Option<char>,
+}
+
+impl Dict {
+    /// Parse the dictionary `name`. `stack` holds the dictionaries that are including it.
+    fn parse(
+        name: &str,
+        text: &str,
+        stack: &mut Vec<String>,
+        read: &dyn Fn(&str) -> Result<String>,
+    ) -> Result<Dict> {
         use Atom::*;
-        let mut candis = HashMap::new();
-        let mut alters = HashMap::new();
-        let mut puncts = HashMap::new();
-        let mut squote = ('\'', '\'');
-        let mut dquote = ('"', '"');
+        if stack.iter().any(|it| it == name) {
+            let mut chain = stack.join(" -> ");
+            chain.push_str(" -> ");
+            chain.push_str(name);
+            return Err(Error::IncludeCycle(chain));
+        }
+        stack.push(name.to_string());
 
+        let mut dict = Dict::default();
+        // spellings inherited from other dictionaries and not yet overridden
+        let mut inherited = HashSet::new();
         let mut atoms = Vec::new();
-        for list in value.lines() {
+        for list in text.lines() {
             if list.is_empty() || list.starts_with("#") {
                 continue;
             }
+            let mut tokens = list.split_whitespace();
+            match tokens.next() {
+                Some("@include" | "@extends") => {
+                    let Some(base) = tokens.next() else {
+                        error!("Missing dictionary name: {list}");
+                        continue;
+                    };
+                    let base = dict_name(base);
+                    let base = Dict::parse(&base, &read(&base)?, stack, read)?;
+                    let parts: Vec<&str> = tokens.collect();
+                    let part = |part| parts.is_empty() || parts.contains(&part);
+                    if part("words") {
+                        for (spelling, _) in base.entries.iter() {
+                            dict.entries.retain(|(s, _)| s != spelling);
+                            inherited.insert(spelling.clone());
+                        }
+                        dict.entries.extend(base.entries);
+                    }
+                    if part("puncts") {
+                        dict.puncts.extend(base.puncts);
+                        dict.separator = base.separator.or(dict.separator);
+                    }
+                    if part("quotes") {
+                        dict.squote = base.squote.or(dict.squote);
+                        dict.dquote = base.dquote.or(dict.dquote);
+                    }
+                    if part("rules") {
+                        dict.rules.extend(base.rules);
+                    }
+                    continue;
+                }
+                Some("@rule") => {
+                    let rule = match (tokens.next(), tokens.next()) {
+                        (Some(pattern), Some(output)) => {
+                            Rule::parse(pattern, &Atom::from(output).to_string())
+                        }
+                        _ => None,
+                    };
+                    match rule {
+                        Some(rule) => dict.rules.push(rule),
+                        None => error!("Unrecogniable rule: {list}"),
+                    }
+                    continue;
+                }
+                Some("@separator") => {
+                    match tokens.next().map(Atom::from) {
+                        Some(Nanch(separator)) => dict.separator = Some(separator),
+                        _ => error!("Unrecogniable separator: {list}"),
+                    }
+                    continue;
+                }
+                Some("@remove") => {
+                    for removed in tokens.map(Atom::from) {
+                        match removed {
+                            Nanch('\'') => dict.squote = None,
+                            Nanch('"') => dict.dquote = None,
+                            Nanch(punct) => {
+                                dict.puncts.remove(&punct);
+                            }
+                            Text(spelling) => dict.entries.retain(|(s, _)| s != spelling),
+                        }
+                    }
+                    continue;
+                }
+                _ => (),
+            }
             atoms.clear();
             atoms.extend(list.split_whitespace().map(Atom::from));
             match atoms[..] {
                 [Nanch('\''), Nanch(open), Nanch(close)] => {
-                    squote = (open, close);
+                    dict.squote = Some((open, close));
                 }
                 [Nanch('"'), Nanch(open), Nanch(close)] => {
-                    dquote = (open, close);
+                    dict.dquote = Some((open, close));
                 }
                 [Nanch('\''), Nanch(dumb)] => {
-                    squote = (dumb, dumb);
+                    dict.squote = Some((dumb, dumb));
                 }
                 [Nanch('"'), Nanch(dumb)] => {
-                    dquote = (dumb, dumb);
+                    dict.dquote = Some((dumb, dumb));
                 }
                 [Nanch(punct), Nanch(remapped)] => {
-                    puncts.insert(punct, remapped);
+                    dict.puncts.insert(punct, remapped);
                 }
-                [Text(spelling), word, ..] => {
-                    // store exact spelling -> word
-                    candis.insert(spelling.to_string(), Exact(word.to_string(), Vec::new()));
-                    // store prefixes -> word
-                    for len in 1..spelling.len() {
-                        let prefix = &spelling[0..len];
-                        match candis.get_mut(prefix) {
-                            None => {
-                                candis.insert(prefix.to_string(), Unique(word.to_string()));
-                            }
-                            Some(Unique(unique)) => {
-                                let duplicates = vec![unique.clone(), word.to_string()];
-                                candis.insert(prefix.to_string(), Duplicates(duplicates));
-                            }
-                            Some(Duplicates(duplicates)) | Some(Exact(_, duplicates)) => {
-                                duplicates.push(word.to_string());
-                            }
-                        }
-                    }
-                    // store word -> alternatives
-                    let word = word.to_string();
-                    for alter in atoms.iter().skip(2) {
-                        match alters.get_mut(&word) {
-                            None => {
-                                alters.insert(word.clone(), vec![alter.to_string()]);
-                            }
-                            Some(alters) => {
-                                alters.push(alter.to_string());
-                            }
-                        }
+                [Text(spelling), _, ..] => {
+                    // an inherited entry is overridden as a whole, while entries written
+                    // in the same file sharing the same spelling are all kept
+                    if inherited.remove(spelling) {
+                        dict.entries.retain(|(s, _)| s != spelling);
                     }
+                    let words = atoms.iter().skip(1).map(Atom::to_string).collect();
+                    dict.entries.push((spelling.to_string(), words));
                 }
                 _ => {
                     error!("Unrecogniable pattern: {list}");
                 }
             }
         }
+        // inherited words are spelled by the rules, if there're any, and so are their
+        // alternatives written in letters
+        if !dict.rules.is_empty() {
+            for (spelling, words) in dict.entries.iter_mut() {
+                if !inherited.contains(spelling) {
+                    continue;
+                }
+                if let Some((word, alters)) = words.split_first_mut() {
+                    *word = transliterate(&dict.rules, spelling);
+                    for alter in alters {
+                        *alter = transliterate(&dict.rules, alter);
+                    }
+                }
+            }
+        }
+        stack.pop();
+        Ok(dict)
+    }
+}
+
+/// Dictionaries can be referred to with or without the extension.
+fn dict_name(name: &str) -> String {
+    if name.ends_with(".dict") {
+        name.to_string()
+    } else {
+        format!("{name}.dict")
+    }
+}
+
+/// Dictionaries that come with the input method. The example ones are only reachable through
+/// `@include`.
+pub fn builtin(name: &str) -> Option<&'static str> {
+    match name {
+        "sitelen.dict" => Some(SITELEN_DICT),
+        "emoji.dict" => Some(EMOJI_DICT),
+        "katakana.dict" => Some(KATAKANA_DICT),
+        _ => None,
+    }
+}
+
+pub fn read_builtin(name: &str) -> Result<String> {
+    builtin(name)
+        .map(str::to_string)
+        .ok_or_else(|| Error::DictMissing(name.to_string()))
+}
+
+impl Schema {
+    /// Load the dictionary `name`. Dictionaries it includes are read by `read`.
+    pub fn load(
+        name: &str,
+        read: &dyn Fn(&str) -> Result<String>,
+        long_glyph: &Settings,
+    ) -> Result<Schema> {
+        let name = dict_name(name);
+        let text = read(&name)?;
+        let mut schema = Schema::from(Dict::parse(&name, &text, &mut Vec::new(), read)?);
+        schema.name = name.trim_end_matches(".dict").to_string();
+        schema.long_glyph = Rules::new(&schema, long_glyph);
+        Ok(schema)
+    }
+}
+
+impl From<&str> for Schema {
+    /// Parse a dictionary whose includes are resolved against the builtin dictionaries.
+    fn from(value: &str) -> Schema {
+        Dict::parse("", value, &mut Vec::new(), &read_builtin)
+            .log_err()
+            .map(Schema::from)
+            .unwrap_or_else(|_| Schema::from(Dict::default()))
+    }
+}
+
+impl From<Dict> for Schema {
+    fn from(dict: Dict) -> Schema {
+        let mut candis = HashMap::new();
+        let mut alters = HashMap::new();
+        let mut spellings = HashMap::new();
+        for (spelling, words) in dict.entries {
+            for word in words.iter() {
+                spellings
+                    .entry(word.clone())
+                    .or_insert_with(|| spelling.clone());
+            }
+            let Some((word, rest)) = words.split_first() else {
+                continue;
+            };
+            // store exact spelling -> word
+            candis.insert(spelling.clone(), Exact(word.clone(), Vec::new()));
+            // store prefixes -> word
+            for len in 1..spelling.len() {
+                let prefix = &spelling[0..len];
+                match candis.get_mut(prefix) {
+                    None => {
+                        candis.insert(prefix.to_string(), Unique(word.clone()));
+                    }
+                    Some(Unique(unique)) => {
+                        let duplicates = vec![unique.clone(), word.clone()];
+                        candis.insert(prefix.to_string(), Duplicates(duplicates));
+                    }
+                    Some(Duplicates(duplicates)) | Some(Exact(_, duplicates)) => {
+                        duplicates.push(word.clone());
+                    }
+                }
+            }
+            // store word -> alternatives
+            for alter in rest {
+                match alters.get_mut(word) {
+                    None => {
+                        alters.insert(word.clone(), vec![alter.clone()]);
+                    }
+                    Some(alters) => {
+                        alters.push(alter.clone());
+                    }
+                }
+            }
+        }
         Schema {
+            name: String::new(),
             candis,
             alters,
-            puncts,
-            squote,
-            dquote,
+            spellings,
+            puncts: dict.puncts,
+            squote: dict.squote.unwrap_or(('\'', '\'')),
+            dquote: dict.dquote.unwrap_or(('"', '"')),
+            rules: dict.rules,
+            separator: dict.separator,
+            long_glyph: Rules::default(),
         }
     }
 }
@@ -168,3 +468,74 @@ fn test_schema(str: &str) {
     println!("{:?}", schema);
     println!()
 }
+
+#[test]
+fn test_include() {
+    let read = |name: &str| -> Result<String> {
+        let text = match name {
+            "base.dict" => "a 󱤀\nala 󱤂\nanu 󱤇\n. 󱦜\n\" 「 」",
+            "child.dict" => "@include base\n@remove anu\nala ❌ 🚫\n[ U+1F58C",
+            "words.dict" => "@include base.dict words",
+            "loop.dict" => "@include knot",
+            "knot.dict" => "@include loop",
+            name => return Err(Error::DictMissing(name.to_string())),
+        };
+        Ok(text.to_string())
+    };
+    let child = Schema::load("child", &read, &Settings::default()).unwrap();
+    assert!(matches!(child.candis.get("a"), Some(Exact(word, _)) if word == "󱤀"));
+    assert!(matches!(child.candis.get("ala"), Some(Exact(word, _)) if word == "❌"));
+    assert_eq!(child.alters["❌"], ["🚫"]);
+    assert!(!child.candis.contains_key("anu"));
+    assert_eq!(child.puncts[&'.'], '󱦜');
+    assert_eq!(child.puncts[&'['], '🖌');
+    assert_eq!(child.dquote, ('「', '」'));
+
+    let words = Schema::load("words", &read, &Settings::default()).unwrap();
+    assert!(words.candis.contains_key("anu"));
+    assert!(words.puncts.is_empty());
+    assert_eq!(words.dquote, ('"', '"'));
+
+    assert!(matches!(
+        Schema::load("loop", &read, &Settings::default()),
+        Err(Error::IncludeCycle(chain)) if chain == "loop.dict -> knot.dict -> loop.dict"
+    ));
+    assert!(matches!(
+        Schema::load("missing", &read, &Settings::default()),
+        Err(Error::DictMissing(_))
+    ));
+}
+
+#[test]
+fn test_rules() {
+    let katakana = Schema::load("katakana", &read_builtin, &Settings::default()).unwrap();
+    assert!(matches!(katakana.candis.get("soweli"), Some(Exact(word, _)) if word == "ソウェリ"));
+    assert!(matches!(katakana.candis.get("ante"), Some(Exact(word, _)) if word == "アンテ"));
+    assert!(
+        matches!(katakana.candis.get("kijetesantakalu"), Some(Exact(word, _)) if word == "キイェテサンタカル")
+    );
+    assert_eq!(katakana.transliterate("Sonja").unwrap(), "ソンヤ");
+    assert_eq!(katakana.puncts[&'.'], '。');
+    assert_eq!(katakana.separator, Some(' '));
+    // the alternatives are spelled by the rules as well
+    let read = |name: &str| -> Result<String> {
+        let text = match name {
+            "base.dict" => "ale 󱤄 ali",
+            "kana.dict" => "@include base\n@rule a ア\n@rule le レ\n@rule li リ",
+            name => return Err(Error::DictMissing(name.to_string())),
+        };
+        Ok(text.to_string())
+    };
+    let kana = Schema::load("kana", &read, &Settings::default()).unwrap();
+    assert!(matches!(kana.candis.get("ale"), Some(Exact(word, _)) if word == "アレ"));
+    assert_eq!(kana.alters["アレ"], ["アリ"]);
+
+    let rules = [
+        Rule::parse("^a", "A").unwrap(),
+        Rule::parse("a$", "Z").unwrap(),
+        Rule::parse("n[aeiou]", "N").unwrap(),
+    ];
+    assert_eq!(transliterate(&rules, "ana"), "ANZ");
+    assert_eq!(transliterate(&rules, "anpa"), "AnpZ");
+    assert!(Rule::parse("^$", "").is_none());
+}
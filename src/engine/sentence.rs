@@ -1,4 +1,11 @@
-use super::{Engine, Suggestion, long_glyph::insert_long_glyph, schema::Candidate::*};
+use std::mem;
+
+use super::{
+    Engine, Suggestion, long_glyph,
+    long_glyph::{insert_long_glyph, insert_long_glyph_after},
+    output,
+    schema::{Candidate::*, Schema},
+};
 use crate::extend::CharExt;
 
 #[derive(Default, Clone)]
@@ -7,6 +14,8 @@ struct Sentence {
     groupping: Vec<usize>,
     score: usize,
     wc: u8,
+    /// What goes between the words, if the schema spaces them
+    separator: Option<char>,
 }
 
 impl Sentence {
@@ -35,6 +44,11 @@ impl Sentence {
         {
             *self.groupping.last_mut().unwrap() += len;
         } else {
+            if !self.output.is_empty()
+                && let Some(separator) = self.separator
+            {
+                self.output.push(separator);
+            }
             self.groupping
                 .push(self.groupping.last().copied().unwrap_or(0) + len);
         }
@@ -54,8 +68,13 @@ impl Sentence {
 
 #[allow(unused)]
 impl Engine {
-    pub(super) fn suggest_sentence(&self, spelling: &str) -> Option<Suggestion> {
-        let mut sents = self.suggest_sentences(spelling);
+    pub(super) fn suggest_sentence(
+        &self,
+        schema: &Schema,
+        spelling: &str,
+        context: &str,
+    ) -> Option<Suggestion> {
+        let mut sents = self.suggest_sentences(schema, spelling);
         let mut best_sent = None;
         let mut highest_score = 0;
         while let Some(sent) = sents.pop() {
@@ -68,23 +87,97 @@ impl Engine {
             }
         }
         let mut best_sent = best_sent?;
-        insert_long_glyph(&mut best_sent.output);
+        let replaced = if context.is_empty() {
+            insert_long_glyph(&mut best_sent.output, &schema.long_glyph);
+            0
+        } else {
+            insert_long_glyph_after(context, &mut best_sent.output, &schema.long_glyph)
+        };
         Some(Suggestion {
             output: best_sent.output,
             groupping: best_sent.groupping,
+            schema: schema.name.clone(),
+            replaced,
         })
     }
 
-    fn suggest_sentences(&self, spelling: &str) -> Vec<Sentence> {
-        let mut sent = Sentence::default();
+    /// Suggest for a spelling of several sentences, segmenting each clause separately and
+    /// rendering the punctuators in between. `None` if any of the clauses is not recognizable.
+    pub(super) fn suggest_sentences_in_block(
+        &self,
+        spelling: &str,
+        preceding: &str,
+    ) -> Option<Suggestion> {
+        let schema = self.schema();
+        let mut block = Suggestion {
+            schema: schema.name.clone(),
+            ..Default::default()
+        };
+        let mut from = 0;
+        let ends = spelling
+            .char_indices()
+            .filter(|(_, ch)| self.is_sentence_punct(*ch))
+            .map(|(i, _)| i)
+            .chain([spelling.len()]);
+        for end in ends {
+            let clause = &spelling[from..end];
+            if !clause.is_empty() {
+                // the first clause may extend the glyphs before it
+                let context = match block.output.is_empty() {
+                    true => long_glyph::context(preceding),
+                    false => "",
+                };
+                let sugg = self.candidates(schema, clause, context, 1).pop();
+                let sugg = match sugg {
+                    Some(sugg) if sugg.groupping.last() == Some(&clause.len()) => sugg,
+                    _ => Suggestion {
+                        output: schema.transliterate(clause)?,
+                        groupping: vec![clause.len()],
+                        ..Default::default()
+                    },
+                };
+                block.replaced += sugg.replaced;
+                block.output.push_str(&sugg.output);
+                block
+                    .groupping
+                    .extend(sugg.groupping.iter().map(|to| from + to));
+            }
+            let Some(punct) = spelling[end..].chars().next() else {
+                break;
+            };
+            // pair up the quotes with the ones before them, in the block or not
+            let paired = self.pair_punct(punct, &format!("{preceding}{}", block.output));
+            let remapped = schema.puncts.get(&punct).copied();
+            let punct = paired.or(remapped).unwrap_or(punct);
+            // and close the brackets left open at the end of the sentence
+            if self.ends_sentence(punct) {
+                let closers = self.closers(&format!("{preceding}{}", block.output));
+                block.output.push_str(&closers);
+            }
+            block.output.push(punct);
+            block.groupping.push(end + 1);
+            from = end + 1;
+        }
+        let preceding = output::unreplaced(preceding, block.replaced);
+        let output = self.separate(schema, mem::take(&mut block.output), preceding);
+        block.output = self.render(schema, output);
+        Some(block)
+    }
+
+    fn suggest_sentences(&self, schema: &Schema, spelling: &str) -> Vec<Sentence> {
+        let mut sent = Sentence {
+            separator: schema.separator,
+            ..Default::default()
+        };
         let mut sents = Vec::new();
-        self.suggest_sentences_recursive(spelling, &mut sent, &mut sents);
+        self.suggest_sentences_recursive(schema, spelling, &mut sent, &mut sents);
         sents.push(sent);
         sents
     }
 
     fn suggest_sentences_recursive(
         &self,
+        schema: &Schema,
         spelling: &str,
         sent: &mut Sentence,
         sents: &mut Vec<Sentence>,
@@ -92,8 +185,7 @@ impl Engine {
         // push leading joiners into the sentence directly
         let mut spelling = spelling;
         for (i, byte) in spelling.as_bytes().iter().copied().enumerate() {
-            if let Some(joiner) = self
-                .schema()
+            if let Some(joiner) = schema
                 .puncts
                 .get(&char::from(spelling.as_bytes()[i]))
                 .copied()
@@ -114,7 +206,7 @@ impl Engine {
 
         let mut found_unique = false;
         for len in (1..=spelling.len()).rev() {
-            match self.schema().candis.get(&spelling[..len]) {
+            match schema.candis.get(&spelling[..len]) {
                 Some(Exact(word, _)) => {
                     exact = Some(word.as_str());
                     exact_len = len;
@@ -139,7 +231,7 @@ impl Engine {
         };
         if let Some(exact) = exact {
             sent.push_exact(exact, exact_len);
-            self.suggest_sentences_recursive(&spelling[exact_len..], sent, sents)
+            self.suggest_sentences_recursive(schema, &spelling[exact_len..], sent, sents)
         }
         if let Some(unique) = unique {
             let sent = if let Some(sent) = extra_sent.as_mut() {
@@ -148,7 +240,7 @@ impl Engine {
                 sent
             };
             sent.push_unique(unique, unique_len);
-            self.suggest_sentences_recursive(&spelling[unique_len..], sent, sents)
+            self.suggest_sentences_recursive(schema, &spelling[unique_len..], sent, sents)
         }
         if let Some(extra_sent) = extra_sent {
             sents.push(extra_sent);
@@ -164,7 +256,7 @@ fn repl() {
     loop {
         buf.clear();
         stdin().read_line(&mut buf).unwrap();
-        let sugg = engine.suggest_sentence(&buf);
+        let sugg = engine.suggest_sentence(engine.schema(), &buf, "");
         if let Some(sugg) = sugg {
             println!("{}", sugg.output)
         } else {
@@ -175,10 +267,13 @@ fn repl() {
 #[test]
 fn test() {
     fn assert_sent(engine: &Engine, spelling: &str, expected: &str) {
-        let sent = engine.suggest_sentence(spelling).unwrap().output;
+        let sent = engine
+            .suggest_sentence(engine.schema(), spelling, "")
+            .unwrap()
+            .output;
         let mut buf = String::new();
         for word in expected.split(' ') {
-            buf.push_str(&engine.suggest(word)[0].output)
+            buf.push_str(&engine.suggest(word, "")[0].output)
         }
         assert_eq!(sent, buf)
     }
@@ -187,3 +282,26 @@ fn test() {
     assert_sent(&engine, "pimaka", "pi ma");
     assert_sent(&engine, "pimkule", "pimeja kule");
 }
+
+#[test]
+fn test_block() {
+    let engine = Engine::default();
+    let glyphs = |text: &str| super::glyphs(engine.schema(), text);
+    let assert = |spelling: &str, expected: &str, groupping: &[usize]| {
+        let block = engine.suggest_sentences_in_block(spelling, "").unwrap();
+        assert_eq!(block.output, glyphs(expected));
+        assert_eq!(block.groupping, groupping);
+    };
+    assert("mipona.sinalape", "mi pona . sina lape", &[2, 6, 7, 11, 15]);
+    assert("mi:ale", "mi : ale", &[2, 3, 6]);
+    assert("\"toki\"", "「 toki 」", &[1, 5, 6]);
+    // brackets are not kept in the block
+    assert!(!"mi[ale".contains(|ch| engine.is_sentence_punct(ch)));
+    // but those left open before it are closed at the end of the sentence
+    let block = engine.suggest_sentences_in_block("ale.", "󱦐").unwrap();
+    assert_eq!(block.output, glyphs("ale ] ."));
+    // quotes are paired with the ones before the block
+    let block = engine.suggest_sentences_in_block("\"", "「󱥬").unwrap();
+    assert_eq!(block.output, "」");
+    assert!(engine.suggest_sentences_in_block("mi.xq", "").is_none());
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::OnceLock,
+    thread,
+};
+
+use crate::{Error, Result, conf, extend::ResultExt, global};
+
+const STACKING_JOINER: char = '\u{F1995}';
+const SCALING_JOINER: char = '\u{F1996}';
+/// How many code points are read from the cmap at most, in case the font is corrupted
+const MAX_CODE_POINTS: usize = 0x110000;
+
+static FONT: OnceLock<Option<Font>> = OnceLock::new();
+
+/// The configured font, if it can be found and read.
+pub fn get() -> Option<&'static Font> {
+    FONT.get_or_init(|| Font::open().log_err().ok()).as_ref()
+}
+
+/// Find and read the font in the background, so that the first keystroke doesn't wait for it.
+pub fn warm_up() {
+    if FONT.get().is_none() {
+        thread::spawn(get);
+    }
+}
+
+/// What's learnt from a TrueType/OpenType font file.
+#[derive(Default)]
+pub struct Font {
+    glyphs: HashMap<char, u16>,
+    advances: Vec<u16>,
+    /// Glyph sequences forming ligatures, including their prefixes
+    ligatures: HashSet<Vec<u16>>,
+    /// Glyphs that are components of any ligature
+    components: HashSet<u16>,
+    fixed_pitch: bool,
+}
+
+impl Font {
+    pub fn open() -> Result<Font> {
+        let conf = &conf::get().font;
+        let path = match conf.path.as_ref() {
+            Some(path) => PathBuf::from(path),
+            None => global::font_path(&conf.name)
+                .ok_or_else(|| Error::FontMissing(conf.name.clone()))?,
+        };
+        let data = fs::read(&path)?;
+        Font::parse(&data).ok_or_else(|| Error::FontCorrupted(path.to_string_lossy().to_string()))
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Font> {
+        let data = Data(data);
+        // use the first font of a collection
+        let base = if data.get(0..4)? == b"ttcf" {
+            data.u32(12)? as usize
+        } else {
+            0
+        };
+        let mut tables = HashMap::new();
+        for i in 0..data.u16(base + 4)? as usize {
+            let record = base + 12 + i * 16;
+            let tag = data.get(record..record + 4)?;
+            tables.insert(tag, data.u32(record + 8)? as usize);
+        }
+        let mut font = Font::default();
+        font.parse_cmap(&data, *tables.get(b"cmap".as_slice())?)?;
+        if let (Some(hhea), Some(hmtx)) = (
+            tables.get(b"hhea".as_slice()),
+            tables.get(b"hmtx".as_slice()),
+        ) {
+            font.parse_hmtx(&data, *hhea, *hmtx);
+        }
+        if let Some(post) = tables.get(b"post".as_slice()) {
+            font.fixed_pitch |= data.u32(post + 12).unwrap_or(0) != 0;
+        }
+        if let Some(os2) = tables.get(b"OS/2".as_slice()) {
+            // bProportion of PANOSE
+            font.fixed_pitch |= data.u8(os2 + 35) == Some(9);
+        }
+        if let Some(gsub) = tables.get(b"GSUB".as_slice()) {
+            font.parse_gsub(&data, *gsub);
+        }
+        Some(font)
+    }
+
+    fn parse_cmap(&mut self, data: &Data, cmap: usize) -> Option<()> {
+        let mut budget = MAX_CODE_POINTS;
+        for i in 0..data.u16(cmap + 2)? as usize {
+            let subtable = cmap + data.u32(cmap + 4 + i * 8 + 4)? as usize;
+            match data.u16(subtable)? {
+                4 => {
+                    let seg_count = data.u16(subtable + 6)? as usize / 2;
+                    let ends = subtable + 14;
+                    let starts = ends + seg_count * 2 + 2;
+                    let deltas = starts + seg_count * 2;
+                    let range_offsets = deltas + seg_count * 2;
+                    for seg in 0..seg_count {
+                        let end = data.u16(ends + seg * 2)? as u32;
+                        let start = data.u16(starts + seg * 2)? as u32;
+                        let delta = data.u16(deltas + seg * 2)?;
+                        let range_offset = data.u16(range_offsets + seg * 2)? as usize;
+                        for code in start..=end.min(0xFFFE) {
+                            if budget == 0 {
+                                return Some(());
+                            }
+                            budget -= 1;
+                            let glyph = if range_offset == 0 {
+                                (code as u16).wrapping_add(delta)
+                            } else {
+                                let addr = range_offsets
+                                    + seg * 2
+                                    + range_offset
+                                    + (code - start) as usize * 2;
+                                match data.u16(addr)? {
+                                    0 => 0,
+                                    glyph => glyph.wrapping_add(delta),
+                                }
+                            };
+                            self.insert_glyph(code, glyph);
+                        }
+                    }
+                }
+                12 => {
+                    for group in 0..data.u32(subtable + 12)? as usize {
+                        let group = subtable + 16 + group * 12;
+                        let start = data.u32(group)?;
+                        let end = data.u32(group + 4)?.min(char::MAX as u32);
+                        let glyph = data.u32(group + 8)?;
+                        for code in start..=end {
+                            if budget == 0 {
+                                return Some(());
+                            }
+                            budget -= 1;
+                            // glyph ids don't go beyond 16 bits
+                            let Some(glyph) = glyph
+                                .checked_add(code - start)
+                                .and_then(|glyph| u16::try_from(glyph).ok())
+                            else {
+                                break;
+                            };
+                            self.insert_glyph(code, glyph);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Some(())
+    }
+
+    fn insert_glyph(&mut self, code: u32, glyph: u16) {
+        if glyph == 0 {
+            return;
+        }
+        if let Some(ch) = char::from_u32(code) {
+            self.glyphs.entry(ch).or_insert(glyph);
+        }
+    }
+
+    fn parse_hmtx(&mut self, data: &Data, hhea: usize, hmtx: usize) -> Option<()> {
+        for i in 0..data.u16(hhea + 34)? as usize {
+            self.advances.push(data.u16(hmtx + i * 4)?);
+        }
+        Some(())
+    }
+
+    /// Collect the ligatures of every lookup regardless of the features they belong to.
+    fn parse_gsub(&mut self, data: &Data, gsub: usize) -> Option<()> {
+        let lookups = gsub + data.u16(gsub + 8)? as usize;
+        for i in 0..data.u16(lookups)? as usize {
+            let lookup = lookups + data.u16(lookups + 2 + i * 2)? as usize;
+            let lookup_type = data.u16(lookup)?;
+            for j in 0..data.u16(lookup + 4)? as usize {
+                let subtable = lookup + data.u16(lookup + 6 + j * 2)? as usize;
+                match lookup_type {
+                    4 => self.parse_ligature_subst(data, subtable),
+                    // extension
+                    7 if data.u16(subtable + 2)? == 4 => {
+                        self.parse_ligature_subst(data, subtable + data.u32(subtable + 4)? as usize)
+                    }
+                    _ => None,
+                };
+            }
+        }
+        Some(())
+    }
+
+    fn parse_ligature_subst(&mut self, data: &Data, subtable: usize) -> Option<()> {
+        let firsts = coverage(data, subtable + data.u16(subtable + 2)? as usize)?;
+        for (i, first) in firsts
+            .into_iter()
+            .enumerate()
+            .take(data.u16(subtable + 4)? as usize)
+        {
+            let set = subtable + data.u16(subtable + 6 + i * 2)? as usize;
+            for j in 0..data.u16(set)? as usize {
+                let ligature = set + data.u16(set + 2 + j * 2)? as usize;
+                let mut sequence = vec![first];
+                for k in 1..data.u16(ligature + 2)? as usize {
+                    sequence.push(data.u16(ligature + 2 + k * 2)?);
+                }
+                self.components.extend(sequence.iter().copied());
+                for len in 2..=sequence.len() {
+                    self.ligatures.insert(sequence[..len].to_vec());
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn advance(&self, ch: char) -> Option<u16> {
+        let glyph = *self.glyphs.get(&ch)? as usize;
+        self.advances.get(glyph).or(self.advances.last()).copied()
+    }
+
+    pub fn monospace(&self) -> bool {
+        self.fixed_pitch
+            || matches!((self.advance('.'), self.advance('m')), (Some(a), Some(b)) if a == b)
+    }
+
+    /// Whether the text can be rendered without tofu. Only the characters in private use
+    /// areas are checked since the others can be rendered by fallback fonts.
+    pub fn supports(&self, text: &str) -> bool {
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if !private_use(ch) {
+                continue;
+            }
+            let Some(glyph) = self.glyphs.get(&ch).copied() else {
+                return false;
+            };
+            // glyphs joined by joiners must form ligatures
+            let mut sequence = vec![glyph];
+            while let Some(joiner) =
+                chars.next_if(|ch| matches!(*ch, STACKING_JOINER | SCALING_JOINER))
+            {
+                let Some(joiner) = self.glyphs.get(&joiner).copied() else {
+                    return false;
+                };
+                sequence.push(joiner);
+                if let Some(next) = chars.next() {
+                    let Some(next) = self.glyphs.get(&next).copied() else {
+                        return false;
+                    };
+                    sequence.push(next);
+                }
+                // the font may join glyphs in other ways when the joiner is not a ligature component
+                if self.components.contains(&joiner) && !self.ligatures.contains(&sequence) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+fn private_use(ch: char) -> bool {
+    matches!(ch, '\u{E000}'..='\u{F8FF}' | '\u{F0000}'..='\u{FFFFD}' | '\u{100000}'..='\u{10FFFD}')
+}
+
+/// Glyphs in the order of their coverage indexes
+fn coverage(data: &Data, coverage: usize) -> Option<Vec<u16>> {
+    let mut glyphs = Vec::new();
+    match data.u16(coverage)? {
+        1 => {
+            for i in 0..data.u16(coverage + 2)? as usize {
+                glyphs.push(data.u16(coverage + 4 + i * 2)?);
+            }
+        }
+        2 => {
+            for i in 0..data.u16(coverage + 2)? as usize {
+                let range = coverage + 4 + i * 6;
+                glyphs.extend(data.u16(range)?..=data.u16(range + 2)?);
+            }
+        }
+        _ => return None,
+    }
+    Some(glyphs)
+}
+
+/// Big-endian reader that never panics
+struct Data<'a>(&'a [u8]);
+
+impl Data<'_> {
+    fn get(&self, range: std::ops::Range<usize>) -> Option<&[u8]> {
+        self.0.get(range)
+    }
+
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.0.get(offset).copied()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(
+            self.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    }
+}
+
+#[test]
+fn test_parse() {
+    fn be(words: &[u32], size: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for word in words {
+            bytes.extend_from_slice(&word.to_be_bytes()[4 - size..]);
+        }
+        bytes
+    }
+    // glyphs: 1 for '.', 2 for 'm', 3 for kala, 4 for the stacking joiner, 5 for lili, 6 for kala^lili
+    let mut cmap = be(&[0, 1, 3, 10], 2);
+    cmap.extend(be(&[12], 4));
+    cmap.extend(be(&[12, 0], 2));
+    cmap.extend(be(&[16 + 8 * 12, 0, 8], 4));
+    for (code, glyph) in [
+        ('.', 1),
+        ('m', 2),
+        ('\u{F1914}', 3),
+        ('\u{F1928}', 5),
+        ('\u{F1995}', 4),
+    ] {
+        cmap.extend(be(&[code as u32, code as u32, glyph], 4));
+    }
+    // corrupted groups
+    cmap.extend(be(&[0x20, 0x1F, 7], 4));
+    cmap.extend(be(&[0x41, u32::MAX, 0xFFFE], 4));
+    cmap.extend(be(&[0x10FFF0, u32::MAX, 8], 4));
+    let mut hhea = vec![0; 34];
+    hhea.extend(be(&[3], 2));
+    let hmtx = be(&[0, 0, 300, 0, 600, 0], 2);
+    let post = vec![0; 32];
+    let gsub = be(
+        &[
+            1, 0, 0, 0, 10, 1, 4, 4, 0, 1, 8, 1, 8, 1, 14, 1, 1, 3, 1, 4, 6, 3, 4, 5,
+        ],
+        2,
+    );
+    let tables = [
+        (b"GSUB", gsub),
+        (b"cmap", cmap),
+        (b"hhea", hhea),
+        (b"hmtx", hmtx),
+        (b"post", post),
+    ];
+    let mut font = be(&[0x00010000], 4);
+    font.extend(be(&[tables.len() as u32, 0, 0, 0], 2));
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, table) in tables.iter() {
+        font.extend_from_slice(*tag);
+        font.extend(be(&[0, offset as u32, table.len() as u32], 4));
+        offset += table.len();
+    }
+    for (_, table) in tables.iter() {
+        font.extend_from_slice(table);
+    }
+
+    let font = Font::parse(&font).unwrap();
+    assert!(!font.glyphs.contains_key(&' '));
+    assert_eq!(font.glyphs[&'B'], 0xFFFF);
+    assert!(!font.glyphs.contains_key(&'C'));
+    assert_eq!(font.glyphs[&char::MAX], 23);
+    assert!(!font.monospace());
+    assert!(font.supports("\u{F1914}\u{F1928}"));
+    assert!(font.supports("\u{F1914}\u{F1995}\u{F1928}"));
+    assert!(!font.supports("\u{F1928}\u{F1995}\u{F1914}"));
+    assert!(!font.supports("\u{F1900}"));
+    assert!(font.supports("🦡"));
+    assert!(Font::parse(&[0; 8]).is_none());
+}
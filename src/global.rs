@@ -10,6 +10,10 @@ use windows::{
     },
     core::GUID,
 };
+use winreg::{
+    RegKey,
+    enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
+};
 
 use crate::{Error, Result, extend::ResultExt};
 
@@ -64,6 +68,42 @@ pub fn hkl_or_us() -> HKL {
     })
 }
 
+/// Find the file of an installed font by its family name.
+pub fn font_path(family: &str) -> Option<PathBuf> {
+    const FONTS: &str = "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\Fonts";
+    // fonts installed for the current user are registered with full paths
+    // while the ones installed for all users are relative to %WINDIR%\Fonts
+    let system_fonts = PathBuf::from(env::var("WINDIR").ok()?).join("Fonts");
+    for hkey in [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE] {
+        let Ok(fonts) = RegKey::predef(hkey).open_subkey(FONTS) else {
+            continue;
+        };
+        for (name, _) in fonts.enum_values().flatten() {
+            // names are like "Fairfax HD Regular (TrueType)" or "Cambria & Cambria Math (TrueType)"
+            let names = name
+                .rsplit_once(" (")
+                .map(|(names, _)| names)
+                .unwrap_or(&name);
+            let matched = names.split(" & ").any(|name| {
+                name.eq_ignore_ascii_case(family)
+                    || name
+                        .strip_suffix(" Regular")
+                        .is_some_and(|name| name.eq_ignore_ascii_case(family))
+            });
+            if !matched {
+                continue;
+            }
+            let Ok(file) = fonts.get_value::<String, _>(&name) else {
+                continue;
+            };
+            let path = system_fonts.join(file);
+            debug!("Found font '{family}' in {}", path.to_string_lossy());
+            return Some(path);
+        }
+    }
+    None
+}
+
 // registration stuff
 pub const IME_NAME: &str = "Ajemi";
 pub const IME_NAME_ASCII: &str = "Ajemi";
@@ -74,15 +114,14 @@ pub const DISPLAY_ATTR_ID: GUID = GUID::from_u128(0xE42647FB_4BF0_4570_9013_7684
 pub const LITE_TRAY_ICON_INDEX: u32 = 0;
 pub const DARK_TRAY_ICON_INDEX: u32 = 1;
 // customization
-pub const CANDI_NUM: usize = 5;
-pub const CANDI_INDEXES: [&str; CANDI_NUM] = ["1", "2", "3", "4", "5"];
-pub const CANDI_INDEX_SUFFIX: &str = ". ";
-pub const CANDI_INDEX_SUFFIX_MONO: &str = ".";
 pub const PREEDIT_DELIMITER: &str = "'";
+/// Appended to the preedit if the composition closes a bracket that is not open
+pub const PREEDIT_WARNING: &str = " !";
 // included text
 pub const DEFAULT_CONF: &str = include_str!("../res/conf.toml");
 pub const SITELEN_DICT: &str = include_str!("../res/dict/sitelen.dict");
 pub const EMOJI_DICT: &str = include_str!("../res/dict/emoji.dict");
+pub const KATAKANA_DICT: &str = include_str!("../res/dict/katakana.dict");
 
 // language IDs
 #[derive(EnumIter)]
@@ -0,0 +1,574 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use crate::conf::{Candidates, Modifier};
+
+//----------------------------------------------------------------------------
+//
+//  Key bindings. Shortcuts like "Ctrl+Shift+N" are parsed and matched against
+//  virtual key codes here, away from Win32, so that they can be tested.
+//
+//----------------------------------------------------------------------------
+
+/// A key that can be bound, other than the modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// An uppercase ASCII letter or a digit
+    Char(char),
+    /// F1 to F24
+    F(u8),
+    Space,
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Left,
+    Up,
+    Right,
+    Down,
+}
+
+use Key::*;
+
+/// The keys with names, along with their virtual key codes.
+/// See https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes for keycodes.
+const NAMED_KEYS: [(&str, Key, u32); 15] = [
+    ("Space", Space, 0x20),
+    ("Enter", Enter, 0x0D),
+    ("Tab", Tab, 0x09),
+    ("Backspace", Backspace, 0x08),
+    ("Escape", Escape, 0x1B),
+    ("Insert", Insert, 0x2D),
+    ("Delete", Delete, 0x2E),
+    ("Home", Home, 0x24),
+    ("End", End, 0x23),
+    ("PageUp", PageUp, 0x21),
+    ("PageDown", PageDown, 0x22),
+    ("Left", Left, 0x25),
+    ("Up", Up, 0x26),
+    ("Right", Right, 0x27),
+    ("Down", Down, 0x28),
+];
+const VK_F1: u32 = 0x70;
+
+impl Key {
+    pub fn from_vk(key_code: u32) -> Option<Key> {
+        if let Some((_, key, _)) = NAMED_KEYS.iter().find(|(_, _, vk)| *vk == key_code) {
+            return Some(*key);
+        }
+        match key_code {
+            // letters and digits share their virtual key codes with their uppercase chars
+            0x30..=0x39 | 0x41..=0x5A => char::from_u32(key_code).map(Char),
+            code @ VK_F1..0x88 => Some(F((code - VK_F1 + 1) as u8)),
+            _ => None,
+        }
+    }
+
+    fn parse(name: &str) -> Option<Key> {
+        if let Some((_, key, _)) = NAMED_KEYS
+            .iter()
+            .find(|(key, ..)| key.eq_ignore_ascii_case(name))
+        {
+            return Some(*key);
+        }
+        if let Some(n) = name.strip_prefix(['F', 'f'])
+            && let Ok(n) = n.parse()
+            && (1..=24).contains(&n)
+        {
+            return Some(F(n));
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) if ch.is_ascii_alphanumeric() => Some(Char(ch.to_ascii_uppercase())),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Char(ch) => write!(f, "{ch}"),
+            F(n) => write!(f, "F{n}"),
+            key => {
+                let (name, ..) = NAMED_KEYS.iter().find(|(_, k, _)| k == key).unwrap();
+                write!(f, "{name}")
+            }
+        }
+    }
+}
+
+/// A key pressed along with the modifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Shortcut {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub key: Key,
+}
+
+impl Shortcut {
+    /// Whether the shortcut types a letter or a punctuator instead.
+    fn is_typing(&self) -> bool {
+        !self.ctrl
+            && !self.alt
+            && matches!(self.key, Char(ch) if ch.is_ascii_alphabetic() || self.shift)
+    }
+
+    /// Whether the shortcut moves the caret, the highlight or the page, or deletes, while
+    /// composing. Those keys work whether Shift is held or not.
+    fn is_editing(&self) -> bool {
+        !self.ctrl
+            && !self.alt
+            && matches!(
+                self.key,
+                Backspace | Delete | Home | End | PageUp | PageDown | Left | Up | Right | Down
+            )
+    }
+
+    /// Whether the shortcut types a space, a digit or a line, or moves the caret or deletes in
+    /// the document, while not composing.
+    fn is_plain(&self) -> bool {
+        !self.ctrl
+            && !self.alt
+            && (matches!(self.key, Char(_) | Space | Enter | Tab) || self.is_editing())
+    }
+
+    /// The modifier it's pressed with, if it's only one of those selecting candidates.
+    fn modifier(&self) -> Option<Modifier> {
+        match (self.ctrl, self.alt, self.shift) {
+            (false, false, false) => Some(Modifier::None),
+            (true, false, false) => Some(Modifier::Ctrl),
+            (false, true, false) => Some(Modifier::Alt),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Shortcut {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut shortcut = Shortcut {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            key: Space,
+        };
+        let (modifiers, key) = match text.rsplit_once('+') {
+            Some((modifiers, key)) => (modifiers, key),
+            None => ("", text),
+        };
+        for modifier in modifiers.split('+').filter(|modifier| !modifier.is_empty()) {
+            let pressed = match modifier.trim().to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => &mut shortcut.ctrl,
+                "alt" => &mut shortcut.alt,
+                "shift" => &mut shortcut.shift,
+                _ => return Err(format!("'{modifier}' in '{text}' is not a modifier.")),
+            };
+            if *pressed {
+                return Err(format!("'{modifier}' is given twice in '{text}'."));
+            }
+            *pressed = true;
+        }
+        shortcut.key =
+            Key::parse(key.trim()).ok_or(format!("'{key}' in '{text}' is not a key."))?;
+        Ok(shortcut)
+    }
+}
+
+impl Display for Shortcut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+/// What a shortcut does
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    NextSchema,
+    PreviousSchema,
+    /// Undo the last commit
+    UndoCommit,
+    /// Toggle keeping several sentences in the composition
+    ToggleSentences,
+    /// Commit the highlighted suggestion
+    Commit,
+    /// Release the spelling as it is
+    Release,
+    /// Release the spelling followed by a space
+    ReleaseWithSpace,
+    /// Throw the composition away
+    Clear,
+    /// Move the caret in the composition, whichever way the candidate list goes
+    CaretLeft,
+    CaretRight,
+}
+
+impl Action {
+    /// Whether it's done while composing, or while not composing.
+    pub fn while_composing(self) -> bool {
+        use Action::*;
+        match self {
+            NextSchema | PreviousSchema | UndoCommit | ToggleSentences => false,
+            Commit | Release | ReleaseWithSpace | Clear | CaretLeft | CaretRight => true,
+        }
+    }
+}
+
+/// The actions bound to shortcuts
+#[derive(Debug)]
+pub struct Keys {
+    bindings: Vec<(Action, Shortcut)>,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        use Action::*;
+        let bindings = [
+            (NextSchema, "Ctrl+Shift+N"),
+            (PreviousSchema, "Ctrl+Shift+P"),
+            (UndoCommit, "Ctrl+Shift+Backspace"),
+            (ToggleSentences, "Ctrl+Shift+S"),
+            (Commit, "Space"),
+            (Release, "Enter"),
+            (ReleaseWithSpace, "Tab"),
+            (Clear, "Escape"),
+            (CaretLeft, "Ctrl+Left"),
+            (CaretRight, "Ctrl+Right"),
+        ];
+        Keys {
+            bindings: bindings
+                .iter()
+                .map(|(action, shortcut)| (*action, shortcut.parse().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+impl Keys {
+    /// The action bound to the shortcut, if it's done in the state.
+    pub fn action(&self, pressed: &Shortcut, composing: bool) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(action, shortcut)| shortcut == pressed && action.while_composing() == composing)
+            .map(|(action, _)| *action)
+    }
+
+    /// Make sure no shortcut is bound twice or takes the place of what is typed, of the keys
+    /// selecting candidates or of the keys moving around the composition.
+    pub fn validate(&self, candidates: &Candidates) -> Result<(), String> {
+        for (i, (action, shortcut)) in self.bindings.iter().enumerate() {
+            if let Some((other, _)) = self.bindings[..i].iter().find(|(_, s)| s == shortcut) {
+                return Err(format!(
+                    "{shortcut} is bound to both {other:?} and {action:?}."
+                ));
+            }
+            if shortcut.is_typing() {
+                return Err(format!(
+                    "{shortcut} for {action:?} would be spelled instead."
+                ));
+            }
+            if action.while_composing() && shortcut.is_editing() {
+                return Err(format!(
+                    "{shortcut} for {action:?} is already used while composing."
+                ));
+            }
+            if !action.while_composing() && shortcut.is_plain() {
+                return Err(format!(
+                    "{shortcut} for {action:?} would take the place of typing."
+                ));
+            }
+            if let (Some(modifier), Char(key)) = (shortcut.modifier(), shortcut.key) {
+                let selecting = match modifier {
+                    Modifier::None => candidates.select(key),
+                    modifier => candidates.select_held(modifier, key),
+                };
+                if selecting.is_some() {
+                    return Err(format!("{shortcut} for {action:?} selects candidates."));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Keys {
+    /// Shortcuts are given by the actions and override the default ones. An empty one unbinds
+    /// the action.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let given = HashMap::<Action, String>::deserialize(deserializer)?;
+        let mut keys = Keys::default();
+        keys.bindings
+            .retain(|(action, _)| !given.contains_key(action));
+        for (action, shortcut) in given {
+            if shortcut.trim().is_empty() {
+                continue;
+            }
+            let shortcut = shortcut.parse().map_err(D::Error::custom)?;
+            keys.bindings.push((action, shortcut));
+        }
+        Ok(keys)
+    }
+}
+
+//----------------------------------------------------------------------------
+//
+//  A modifier tapped on its own toggles the input method. Taps are told apart
+//  from chords and from the modifier held for too long.
+//
+//----------------------------------------------------------------------------
+
+/// A modifier key that toggles the input method when tapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapKey {
+    Shift,
+    LShift,
+    RShift,
+    Ctrl,
+}
+
+const VK_SHIFT: u32 = 0x10;
+const VK_CONTROL: u32 = 0x11;
+const VK_LSHIFT: u32 = 0xA0;
+const VK_RSHIFT: u32 = 0xA1;
+const VK_LCONTROL: u32 = 0xA2;
+const VK_RCONTROL: u32 = 0xA3;
+/// The right shift key shares its virtual key code with the left one
+const RSHIFT_SCANCODE: u32 = 0x36;
+
+impl TapKey {
+    /// Whether the key event is of the key. The scan code in `lparam` tells the right shift key
+    /// from the left one.
+    pub fn matches(self, key_code: u32, lparam: u32) -> bool {
+        let right = (lparam >> 16) & 0xFF == RSHIFT_SCANCODE;
+        match self {
+            TapKey::Shift => matches!(key_code, VK_SHIFT | VK_LSHIFT | VK_RSHIFT),
+            TapKey::LShift => key_code == VK_LSHIFT || key_code == VK_SHIFT && !right,
+            TapKey::RShift => key_code == VK_RSHIFT || key_code == VK_SHIFT && right,
+            TapKey::Ctrl => matches!(key_code, VK_CONTROL | VK_LCONTROL | VK_RCONTROL),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TapState {
+    Idle,
+    /// The modifier went down on its own at the instant
+    Down(Instant),
+    /// The modifier is held along with other keys
+    Chord,
+}
+
+/// Tracks the key events to tell if the modifier is tapped. The events may come twice, once to
+/// be tested and once to be handled, so each of them only counts once.
+#[derive(Debug)]
+pub struct Tap {
+    timeout: Duration,
+    state: TapState,
+    /// The other keys held down
+    held: Vec<u32>,
+}
+
+impl Tap {
+    pub fn new(timeout: Duration) -> Tap {
+        Tap {
+            timeout,
+            state: TapState::Idle,
+            held: Vec::new(),
+        }
+    }
+
+    /// A key goes down. `tapped` tells if it's the modifier. Keys held down go down repeatedly.
+    pub fn key_down(&mut self, key_code: u32, tapped: bool, now: Instant) {
+        if tapped {
+            if self.state == TapState::Idle {
+                self.state = match self.held.is_empty() {
+                    true => TapState::Down(now),
+                    false => TapState::Chord,
+                };
+            }
+        } else {
+            if self.state != TapState::Idle {
+                self.state = TapState::Chord;
+            }
+            if !self.held.contains(&key_code) {
+                self.held.push(key_code);
+            }
+        }
+    }
+
+    /// A key goes up. Returns whether it completes a tap of the modifier.
+    pub fn key_up(&mut self, key_code: u32, tapped: bool, now: Instant) -> bool {
+        if !tapped {
+            self.held.retain(|key| *key != key_code);
+            return false;
+        }
+        let state = self.state;
+        self.state = TapState::Idle;
+        matches!(state, TapState::Down(down) if now.duration_since(down) <= self.timeout)
+    }
+
+    /// Forget the keys held down, whose key up events may never come.
+    pub fn reset(&mut self) {
+        self.state = TapState::Idle;
+        self.held.clear();
+    }
+}
+
+#[test]
+fn test_shortcut() {
+    let shortcut = |text: &str| text.parse::<Shortcut>();
+    assert_eq!(
+        shortcut("Ctrl+Shift+N"),
+        Ok(Shortcut {
+            ctrl: true,
+            alt: false,
+            shift: true,
+            key: Char('N'),
+        })
+    );
+    assert_eq!(
+        shortcut("ctrl + alt + f5").unwrap().to_string(),
+        "Ctrl+Alt+F5"
+    );
+    assert_eq!(shortcut("pageup").unwrap().key, PageUp);
+    assert!(shortcut("Ctrl+Ctrl+N").is_err());
+    assert!(shortcut("Super+N").is_err());
+    assert!(shortcut("Ctrl+").is_err());
+    assert!(shortcut("Ctrl+;").is_err());
+    // matched against virtual key codes
+    assert_eq!(Key::from_vk(0x4E), Some(Char('N')));
+    assert_eq!(Key::from_vk(0x33), Some(Char('3')));
+    assert_eq!(Key::from_vk(0x71), Some(F(2)));
+    assert_eq!(Key::from_vk(0x08), Some(Backspace));
+    assert_eq!(Key::from_vk(0xBA), None);
+}
+
+#[test]
+fn test_keys() {
+    let keys = |text: &str| toml::from_str::<Keys>(text);
+    let pressed = |text: &str| text.parse::<Shortcut>().unwrap();
+    let default = Keys::default();
+    assert!(default.validate(&Candidates::default()).is_ok());
+    assert_eq!(
+        default.action(&pressed("Ctrl+Shift+N"), false),
+        Some(Action::NextSchema)
+    );
+    assert_eq!(default.action(&pressed("Ctrl+Shift+N"), true), None);
+    assert_eq!(
+        default.action(&pressed("Ctrl+Left"), true),
+        Some(Action::CaretLeft)
+    );
+    assert_eq!(
+        default.action(&pressed("Enter"), true),
+        Some(Action::Release)
+    );
+    // overriding and unbinding
+    let keys = keys("release = 'Shift+Enter'\nclear = ''").unwrap();
+    assert_eq!(keys.action(&pressed("Enter"), true), None);
+    assert_eq!(
+        keys.action(&pressed("Shift+Enter"), true),
+        Some(Action::Release)
+    );
+    assert_eq!(keys.action(&pressed("Escape"), true), None);
+    assert!(toml::from_str::<Keys>("release = 'Ctrl+Nope'").is_err());
+    assert!(toml::from_str::<Keys>("nope = 'Ctrl+N'").is_err());
+    // conflicts
+    let conflicting = |text: &str| {
+        toml::from_str::<Keys>(text)
+            .unwrap()
+            .validate(&Candidates::default())
+            .is_err()
+    };
+    assert!(conflicting("clear = 'Ctrl+Shift+N'"));
+    assert!(conflicting("clear = 'Shift+Q'"));
+    assert!(conflicting("clear = '3'"));
+    assert!(conflicting("clear = 'Shift+3'"));
+    assert!(!conflicting("clear = 'Ctrl+3'"));
+    assert!(conflicting("clear = 'Shift+Backspace'"));
+    assert!(conflicting("release = 'PageDown'"));
+    assert!(!conflicting("release = 'Ctrl+PageDown'"));
+    assert!(!conflicting("undo_commit = 'Alt+Backspace'"));
+    // the actions while not composing can't take the keys typing
+    assert!(conflicting("next_schema = 'Space'"));
+    assert!(conflicting("next_schema = 'Enter'"));
+    assert!(conflicting("next_schema = 'Tab'"));
+    assert!(conflicting("undo_commit = 'Backspace'"));
+    assert!(conflicting("undo_commit = 'Shift+Backspace'"));
+    assert!(conflicting("undo_commit = 'Delete'"));
+    assert!(conflicting("previous_schema = 'Home'"));
+    assert!(conflicting("previous_schema = 'PageUp'"));
+    assert!(conflicting("toggle_sentences = 'Left'"));
+    assert!(conflicting("toggle_sentences = '6'"));
+    assert!(!conflicting("toggle_sentences = 'F6'"));
+    // punctuators can't be bound at all
+    assert!(toml::from_str::<Keys>("clear = '-'").is_err());
+}
+
+#[test]
+fn test_tap() {
+    const VK_A: u32 = 0x41;
+    let start = Instant::now();
+    let ms = |ms| start + Duration::from_millis(ms);
+    let mut tap = Tap::new(Duration::from_millis(500));
+    // the events may come twice
+    tap.key_down(VK_SHIFT, true, ms(0));
+    tap.key_down(VK_SHIFT, true, ms(0));
+    assert!(tap.key_up(VK_SHIFT, true, ms(100)));
+    assert!(!tap.key_up(VK_SHIFT, true, ms(100)));
+    // held for too long
+    tap.key_down(VK_SHIFT, true, ms(1000));
+    tap.key_down(VK_SHIFT, true, ms(1300));
+    assert!(!tap.key_up(VK_SHIFT, true, ms(1600)));
+    // part of a chord, even after the other key goes up
+    tap.key_down(VK_SHIFT, true, ms(2000));
+    tap.key_down(VK_A, false, ms(2050));
+    assert!(!tap.key_up(VK_A, false, ms(2100)));
+    tap.key_down(VK_SHIFT, true, ms(2150));
+    assert!(!tap.key_up(VK_SHIFT, true, ms(2200)));
+    // pressed while another key is held
+    tap.key_down(VK_CONTROL, false, ms(3000));
+    tap.key_down(VK_SHIFT, true, ms(3050));
+    assert!(!tap.key_up(VK_SHIFT, true, ms(3100)));
+    tap.key_up(VK_CONTROL, false, ms(3150));
+    tap.key_down(VK_SHIFT, true, ms(3200));
+    assert!(tap.key_up(VK_SHIFT, true, ms(3250)));
+    // the key up events of other keys may get lost
+    tap.key_down(VK_A, false, ms(4000));
+    tap.reset();
+    tap.key_down(VK_SHIFT, true, ms(4100));
+    assert!(tap.key_up(VK_SHIFT, true, ms(4150)));
+    // left and right
+    let left = 0x2A << 16;
+    let right = RSHIFT_SCANCODE << 16;
+    assert!(TapKey::LShift.matches(VK_SHIFT, left));
+    assert!(!TapKey::LShift.matches(VK_SHIFT, right));
+    assert!(TapKey::RShift.matches(VK_SHIFT, right));
+    assert!(TapKey::RShift.matches(VK_RSHIFT, 0));
+    assert!(TapKey::Shift.matches(VK_SHIFT, right));
+    assert!(!TapKey::Shift.matches(VK_CONTROL, 0));
+    assert!(TapKey::Ctrl.matches(VK_RCONTROL, 0));
+}
@@ -1,8 +1,10 @@
 #![allow(non_camel_case_types)]
 mod conf;
-mod engine;
+pub mod engine;
 mod extend;
+mod font;
 mod global;
+mod keys;
 mod logger;
 mod register;
 mod tsf;
@@ -146,8 +148,18 @@ pub enum Error {
     LayoutInvalid,
     #[error("Failed to parse '{0}'. {1:?}")]
     ParseError(&'static str, toml::de::Error),
+    #[error("Invalid [{0}] in 'conf.toml'. {1}")]
+    ConfInvalid(&'static str, String),
     #[error("install.dat is corrupted. {0}")]
     InstallDatCorrupted(ParseIntError),
+    #[error("Dictionary '{0}' is not found.")]
+    DictMissing(String),
+    #[error("Dictionaries are including each other: {0}.")]
+    IncludeCycle(String),
+    #[error("Font '{0}' is not found.")]
+    FontMissing(String),
+    #[error("Font file '{0}' is corrupted.")]
+    FontCorrupted(String),
 }
 
 // bonus From<E> for alternative windows Error types
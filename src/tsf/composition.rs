@@ -1,4 +1,7 @@
-use std::ffi::OsString;
+use std::{
+    ffi::OsString,
+    time::{Duration, Instant},
+};
 
 use log::{debug, trace};
 use windows::{
@@ -10,7 +13,43 @@ use windows::{
 };
 
 use super::{TextService, TextServiceInner, edit_session};
-use crate::{PREEDIT_DELIMITER, extend::OsStrExt2};
+use crate::{
+    PREEDIT_DELIMITER, PREEDIT_WARNING,
+    conf::{self, Backspace},
+    engine::Suggestion,
+    extend::OsStrExt2,
+};
+
+/// How many UTF-16 units before the composition are read for long glyphs to extend over
+const CONTEXT_LEN: usize = 32;
+/// How many UTF-16 units before the caret are read to compose them again
+const RECOMPOSE_LEN: usize = 256;
+
+/// How many candidates a page of the candidate list holds
+fn page_size() -> usize {
+    conf::get().candidates.page_size
+}
+
+/// The state of the composition before a candidate is selected for the start of the spelling
+pub struct Selection {
+    spelling: String,
+    /// Where the rest of the spelling starts
+    rest: usize,
+    selected: String,
+    preceding: String,
+    replaced: usize,
+    suggestions: Vec<Suggestion>,
+}
+
+/// The last commit, for it to be undone
+pub struct Commit {
+    /// The state the composition started with
+    origin: Selection,
+    output: String,
+    /// How many UTF-16 code units before the composition were taken over
+    taken: usize,
+    time: Instant,
+}
 
 //----------------------------------------------------------------------------
 //
@@ -25,7 +64,20 @@ impl TextServiceInner {
     pub fn start_composition(&mut self) -> Result<()> {
         let composition =
             edit_session::start_composition(self.tid, self.context()?, &self.interface()?)?;
+        // remember the glyphs before the composition for long glyphs to extend over
+        self.preceding.clear();
+        if let Ok(range) = unsafe { composition.GetRange() }
+            && let Ok(text) = edit_session::get_preceding_text(
+                self.tid,
+                self.context()?,
+                Some(&range),
+                CONTEXT_LEN,
+            )
+        {
+            self.preceding = String::from_utf16_lossy(&text);
+        }
         self.composition = Some(composition);
+        self.just_committed = None;
         if let Some((x, y)) = self.get_pos() {
             self.candidate_list()?.locate(x, y)?;
         }
@@ -41,29 +93,59 @@ impl TextServiceInner {
         }
         self.composition = None;
         self.spelling.clear();
+        self.caret = 0;
+        self.focus = 0;
         self.selected.clear();
+        self.preceding.clear();
+        self.replaced = 0;
+        self.selections.clear();
         self.suggestions.clear();
+        self.engine.end_lookup();
         self.candidate_list()?.hide();
         Ok(())
     }
 
+    /// Start a composition that is looked up in another schema. The active schema takes over
+    /// again once the composition ends.
+    pub fn start_lookup(&mut self, schema: &str) -> Result<()> {
+        self.engine.start_lookup(schema);
+        self.start_composition()?;
+        self.udpate_preedit()
+    }
+
     fn udpate_preedit(&mut self) -> Result<()> {
         self.preedit.clear();
+        if self.engine.looking_up()
+            && let Some(lookup) = conf::get().lookup.as_ref()
+        {
+            self.preedit.push(lookup.prefix);
+        }
         self.preedit.push_str(&self.selected);
-        if self.suggestions.is_empty() {
-            self.preedit.push_str(&self.spelling);
-        } else {
+        // where the caret is in the preedit
+        let mut caret = self.preedit.len() + self.caret;
+        if let Some(sugg) = self.suggestions.get(self.focus) {
             let mut from = 0;
-            for to in &self.suggestions[0].groupping {
+            for to in &sugg.groupping {
+                if (from..=*to).contains(&self.caret) {
+                    caret = self.preedit.len() + self.caret - from;
+                }
                 self.preedit.push_str(&self.spelling[from..*to]);
                 self.preedit.push_str(PREEDIT_DELIMITER);
                 from = *to;
             }
             if from != self.spelling.len() {
+                if self.caret > from {
+                    caret = self.preedit.len() + self.caret - from;
+                }
                 self.preedit.push_str(&self.spelling[from..])
             } else {
                 self.preedit.pop();
             }
+            if self.engine.unbalanced(&self.preceding, &sugg.output) {
+                self.preedit.push_str(PREEDIT_WARNING);
+            }
+        } else {
+            self.preedit.push_str(&self.spelling);
         }
         let range = unsafe { self.composition()?.GetRange()? };
         let text = OsString::from(&self.preedit).to_wchars();
@@ -73,7 +155,14 @@ impl TextServiceInner {
             range,
             &text,
             self.display_attribute.as_ref(),
-        )
+        )?;
+        // `set_text` leaves the caret at the end
+        if self.caret != self.spelling.len() {
+            let range = unsafe { self.composition()?.GetRange()? };
+            let offset = self.preedit[..caret].encode_utf16().count();
+            edit_session::set_caret(self.tid, self.context()?, range, offset)?;
+        }
+        Ok(())
     }
 
     fn update_candidate_list(&mut self) -> Result<()> {
@@ -82,7 +171,11 @@ impl TextServiceInner {
         if self.suggestions.is_empty() {
             candidate_list.hide();
         } else {
-            candidate_list.show(&self.suggestions)?;
+            let page = self.focus / page_size();
+            let from = page * page_size();
+            let to = self.suggestions.len().min(from + page_size());
+            let more = self.suggestions.len() > to;
+            candidate_list.show(&self.suggestions[from..to], self.focus - from, page, more)?;
             if let Some((x, y)) = self.get_pos() {
                 candidate_list.locate(x, y)?;
             }
@@ -90,7 +183,17 @@ impl TextServiceInner {
         Ok(())
     }
 
+    /// Set the text to commit, replacing the chars before the composition that the selected
+    /// suggestions took over.
     fn set_text(&self, text: &str) -> Result<()> {
+        if self.replaced != 0 {
+            edit_session::extend_composition(
+                self.tid,
+                self.context()?,
+                self.composition()?,
+                self.replaced,
+            )?;
+        }
         let text = OsString::from(text).to_wchars();
         let range = unsafe { self.composition()?.GetRange()? };
         edit_session::set_text(self.tid, self.context()?, range, &text, None)
@@ -115,78 +218,359 @@ impl TextServiceInner {
 // handle input and transit state
 // calling these function while not composing would cause the program to crash
 impl TextServiceInner {
+    /// Insert the char at the caret.
     pub fn push(&mut self, ch: char) -> Result<()> {
-        self.spelling.push(ch);
-        self.suggestions = self.engine.suggest(&self.spelling);
-        self.udpate_preedit()?;
-        self.update_candidate_list()?;
-        Ok(())
+        self.spelling.insert(self.caret, ch);
+        self.caret += ch.len_utf8();
+        self.respell()
     }
 
+    /// Delete the char before the caret.
     pub fn pop(&mut self) -> Result<()> {
-        // todo pop can be used to revert selection
-        self.spelling.pop();
+        if conf::get().behavior.backspace == Backspace::Selection
+            && self.caret == self.spelling.len()
+            && self.unselect()
+        {
+            self.udpate_preedit()?;
+            return self.update_candidate_list();
+        }
+        // nothing is typed after the lookup prefix
         if self.spelling.is_empty() {
             return self.abort();
         }
-        self.suggestions = self.engine.suggest(&self.spelling);
+        let Some(ch) = self.spelling[..self.caret].chars().next_back() else {
+            return Ok(());
+        };
+        self.caret -= ch.len_utf8();
+        self.spelling.remove(self.caret);
+        self.respell()
+    }
+
+    /// Delete the char after the caret.
+    pub fn delete(&mut self) -> Result<()> {
+        if self.caret == self.spelling.len() {
+            return Ok(());
+        }
+        self.spelling.remove(self.caret);
+        self.respell()
+    }
+
+    /// Look up the spelling again after it's edited.
+    fn respell(&mut self) -> Result<()> {
+        if self.spelling.is_empty() {
+            return self.abort();
+        }
+        self.suggestions = self.engine.suggest(&self.spelling, &self.preceding);
+        self.focus = 0;
         self.udpate_preedit()?;
         self.update_candidate_list()?;
         Ok(())
     }
 
-    /// Commit the 1st suggestion, keeping the unrecognizable trailing characters
+    pub fn caret_left(&mut self) -> Result<()> {
+        if let Some(ch) = self.spelling[..self.caret].chars().next_back() {
+            self.caret -= ch.len_utf8();
+            self.udpate_preedit()?;
+        }
+        Ok(())
+    }
+
+    pub fn caret_right(&mut self) -> Result<()> {
+        if let Some(ch) = self.spelling[self.caret..].chars().next() {
+            self.caret += ch.len_utf8();
+            self.udpate_preedit()?;
+        }
+        Ok(())
+    }
+
+    pub fn caret_home(&mut self) -> Result<()> {
+        self.caret = 0;
+        self.udpate_preedit()
+    }
+
+    pub fn caret_end(&mut self) -> Result<()> {
+        self.caret = self.spelling.len();
+        self.udpate_preedit()
+    }
+
+    /// Commit the highlighted suggestion, keeping the unrecognizable trailing characters
     pub fn commit(&mut self) -> Result<()> {
         if self.suggestions.is_empty() {
             self.force_release(' ')
         } else {
-            self.select(0)
+            self.select(self.focus)
         }
     }
 
-    /// Commit the 1st suggestion and release the unrecognizable trailing characters.
+    pub fn focus_next(&mut self) -> Result<()> {
+        self.focus_on(self.focus + 1)
+    }
+
+    pub fn focus_previous(&mut self) -> Result<()> {
+        match self.focus.checked_sub(1) {
+            Some(index) => self.focus_on(index),
+            None => Ok(()),
+        }
+    }
+
+    pub fn next_page(&mut self) -> Result<()> {
+        self.focus_on((self.focus / page_size() + 1) * page_size())
+    }
+
+    pub fn previous_page(&mut self) -> Result<()> {
+        match (self.focus / page_size()).checked_sub(1) {
+            Some(page) => self.focus_on(page * page_size()),
+            None => Ok(()),
+        }
+    }
+
+    /// Highlight the suggestion if there is one, looking up the page it's on.
+    fn focus_on(&mut self, index: usize) -> Result<()> {
+        // and the first one of the next page, to tell if there is a next page
+        let len = (index / page_size() + 1) * page_size() + 1;
+        self.engine
+            .suggest_more(&self.spelling, &self.preceding, &mut self.suggestions, len);
+        if index >= self.suggestions.len() {
+            return Ok(());
+        }
+        self.focus = index;
+        self.udpate_preedit()?;
+        self.update_candidate_list()
+    }
+
+    /// Bring the glyphs just committed back into a composition, led by the suggestion they came
+    /// from. Returns `false` if there's nothing to compose again.
+    pub fn recompose(&mut self) -> Result<bool> {
+        let Some(caret) = self.just_committed.take() else {
+            return Ok(false);
+        };
+        // the caret may be moved by the mouse
+        if !edit_session::is_caret(self.tid, self.context()?, &caret)? {
+            return Ok(false);
+        }
+        let text =
+            edit_session::get_preceding_text(self.tid, self.context()?, None, RECOMPOSE_LEN)?;
+        let text = String::from_utf16_lossy(&text);
+        let Some(respelling) = self.engine.respell(&text) else {
+            return Ok(false);
+        };
+        self.start_composition()?;
+        let len = text[respelling.from..].encode_utf16().count();
+        edit_session::extend_composition(self.tid, self.context()?, self.composition()?, len)?;
+        self.suggestions = self.engine.suggest_respelled(&text, &respelling);
+        self.preceding = text;
+        self.preceding.truncate(respelling.from);
+        self.spelling = respelling.spelling;
+        self.caret = self.spelling.len();
+        self.focus = 0;
+        self.udpate_preedit()?;
+        self.update_candidate_list()?;
+        Ok(true)
+    }
+
+    /// Remember where the caret is after a commit, if the commit can be composed again.
+    fn mark_committed(&mut self) {
+        self.just_committed = None;
+        if !conf::get().behavior.recompose {
+            return;
+        }
+        let Ok(context) = self.context() else {
+            return;
+        };
+        let respellable = edit_session::get_preceding_text(self.tid, context, None, RECOMPOSE_LEN)
+            .is_ok_and(|text| {
+                self.engine
+                    .respell(&String::from_utf16_lossy(&text))
+                    .is_some()
+            });
+        if respellable {
+            self.just_committed = edit_session::get_caret(self.tid, context).ok();
+        }
+    }
+
+    /// Whether there are glyphs just committed before the caret to compose again, as found at
+    /// the commit. Nothing is read from the document, as it's asked while testing keys.
+    pub fn recomposable(&self) -> bool {
+        self.just_committed.is_some()
+    }
+
+    /// Commit the highlighted suggestion and release the unrecognizable trailing characters.
     pub fn force_commit(&mut self, ch: char) -> Result<()> {
         if self.suggestions.is_empty() {
             self.force_release(ch)
         } else {
-            let sugg = self.suggestions.first().unwrap();
-            self.selected.push_str(&sugg.output);
-            let last = *sugg.groupping.last().unwrap();
+            let last = *self.suggestions[self.focus].groupping.last().unwrap();
+            let snapshot = self.snapshot(last);
+            self.accept(self.focus);
             if last != self.spelling.len() {
                 self.selected.push(' ');
                 self.selected.push_str(&self.spelling[last..])
             }
+            // close the brackets left open at the end of the sentence, in the composition or not
+            if self.engine.ends_sentence(ch) {
+                let closers = self
+                    .engine
+                    .closers(&format!("{}{}", self.preceding, self.selected));
+                self.selected.push_str(&closers);
+            }
             self.selected.push(ch);
+            self.remember_commit(snapshot);
             self.set_text(&self.selected)?;
-            self.end_composition()
+            self.end_composition()?;
+            self.mark_committed();
+            Ok(())
         }
     }
 
-    /// Select the desired suggestion by pressing numbers.
+    /// Select the suggestion labeled `index` on the page by pressing numbers.
+    pub fn select_on_page(&mut self, index: usize) -> Result<()> {
+        if index >= page_size() {
+            return Ok(());
+        }
+        self.select(self.focus / page_size() * page_size() + index)
+    }
+
+    /// Select the desired suggestion.
     pub fn select(&mut self, index: usize) -> Result<()> {
         if index >= self.suggestions.len() {
             return Ok(());
         }
         let sugg = self.suggestions.get(index).unwrap();
+        trace!("select({index}) from schema '{}'", sugg.schema);
         let last = *sugg.groupping.last().unwrap();
+        let snapshot = self.snapshot(last);
+        self.accept(index);
         if last == self.spelling.len() {
-            if self.selected.is_empty() {
-                self.set_text(&sugg.output)?;
-            } else {
-                self.selected.push_str(&sugg.output);
-                self.set_text(&self.selected)?;
-            };
-            self.end_composition()
+            self.remember_commit(snapshot);
+            self.set_text(&self.selected)?;
+            self.end_composition()?;
+            self.mark_committed();
+            Ok(())
         } else {
-            self.selected.push_str(&sugg.output);
+            self.selections.push(snapshot);
             // TODO strip off the begining instead of re allocate
             self.spelling = self.spelling[last..].to_string();
-            self.suggestions = self.engine.suggest(&self.spelling);
+            self.caret = self.caret.saturating_sub(last);
+            self.suggestions = self.engine.suggest(&self.spelling, &self.preceding);
+            self.focus = 0;
             self.udpate_preedit()?;
             self.update_candidate_list()
         }
     }
 
+    /// The state before selecting a candidate spelled by `spelling[..rest]`.
+    fn snapshot(&self, rest: usize) -> Selection {
+        Selection {
+            spelling: self.spelling.clone(),
+            rest,
+            selected: self.selected.clone(),
+            preceding: self.preceding.clone(),
+            replaced: self.replaced,
+            suggestions: self.suggestions.clone(),
+        }
+    }
+
+    /// Remember what's about to be committed along with the state the composition started with.
+    fn remember_commit(&mut self, last: Selection) {
+        let origin = match self.selections.is_empty() {
+            true => last,
+            false => self.selections.swap_remove(0),
+        };
+        self.last_commit = Some(Commit {
+            origin,
+            output: self.selected.clone(),
+            taken: self.replaced,
+            time: Instant::now(),
+        });
+    }
+
+    /// Whether the last commit is right before the caret and recent enough to be undone.
+    pub fn undoable(&self) -> bool {
+        self.last_commit_text().is_some()
+    }
+
+    /// The text before the caret if it ends with the last commit that's recent enough.
+    fn last_commit_text(&self) -> Option<String> {
+        let commit = self.last_commit.as_ref()?;
+        let window = Duration::from_secs(conf::get().behavior.undo_window);
+        if commit.time.elapsed() > window {
+            return None;
+        }
+        let context = self.context().ok()?;
+        let text = edit_session::get_preceding_text(self.tid, context, None, RECOMPOSE_LEN).ok()?;
+        let text = String::from_utf16_lossy(&text);
+        text.ends_with(&commit.output).then_some(text)
+    }
+
+    /// Replace the last commit with the composition it came from. Returns `false` if it's gone
+    /// or too old.
+    pub fn undo_commit(&mut self) -> Result<bool> {
+        if self.last_commit_text().is_none() {
+            return Ok(false);
+        }
+        let Some(commit) = self.last_commit.take() else {
+            return Ok(false);
+        };
+        self.start_composition()?;
+        let len = commit.output.encode_utf16().count();
+        edit_session::extend_composition(self.tid, self.context()?, self.composition()?, len)?;
+        // the chars taken over are composed again as they were
+        let origin = commit.origin;
+        let mut taken = 0;
+        let from = origin
+            .preceding
+            .char_indices()
+            .rev()
+            .take_while(|(_, ch)| {
+                taken += ch.len_utf16();
+                taken <= commit.taken
+            })
+            .last()
+            .map_or(origin.preceding.len(), |(i, _)| i);
+        self.selected = origin.preceding[from..].to_string();
+        self.spelling = origin.spelling;
+        self.caret = self.spelling.len();
+        self.preceding = origin.preceding;
+        self.replaced = 0;
+        self.suggestions = origin.suggestions;
+        self.focus = 0;
+        self.udpate_preedit()?;
+        self.update_candidate_list()?;
+        Ok(true)
+    }
+
+    /// Go back to the state before the last selection if nothing is typed after it.
+    fn unselect(&mut self) -> bool {
+        let Some(selection) = self.selections.last() else {
+            return false;
+        };
+        if selection.spelling[selection.rest..] != self.spelling {
+            return false;
+        }
+        let selection = self.selections.pop().unwrap();
+        self.spelling = selection.spelling;
+        self.caret = self.spelling.len();
+        self.selected = selection.selected;
+        self.preceding = selection.preceding;
+        self.replaced = selection.replaced;
+        self.suggestions = selection.suggestions;
+        self.focus = 0;
+        true
+    }
+
+    /// Append the suggestion to the selected part, in place of the chars it takes over.
+    fn accept(&mut self, index: usize) {
+        let sugg = &self.suggestions[index];
+        for _ in 0..sugg.replaced {
+            let ch = self.preceding.pop();
+            if self.selected.pop().is_none() {
+                self.replaced += ch.map_or(1, char::len_utf16);
+            }
+        }
+        self.selected.push_str(&sugg.output);
+        self.preceding.push_str(&sugg.output);
+    }
+
     // Release the raw ascii chars
     pub fn release(&mut self) -> Result<()> {
         if self.selected.is_empty() {
@@ -199,21 +583,29 @@ impl TextServiceInner {
         self.end_composition()
     }
 
-    fn force_release(&mut self, ch: char) -> Result<()> {
+    pub fn force_release(&mut self, ch: char) -> Result<()> {
+        self.spelling.push(ch);
         if self.selected.is_empty() {
-            self.spelling.push(ch);
             self.set_text(&self.spelling)?;
         } else {
             self.selected.push(' ');
             self.selected.push_str(&self.spelling);
-            self.selected.push(ch);
             self.set_text(&self.selected)?;
         }
         self.end_composition()
     }
 
+    /// Throw the composition away, along with the glyphs brought back into it.
+    pub fn clear(&mut self) -> Result<()> {
+        self.just_committed = None;
+        let range = unsafe { self.composition()?.GetRange()? };
+        edit_session::set_text(self.tid, self.context()?, range, &[], None)?;
+        self.end_composition()
+    }
+
     // Interupted. Abort everything.
     pub fn abort(&mut self) -> Result<()> {
+        self.just_committed = None;
         if self.selected.is_empty() {
             let _ = self.set_text(&self.spelling);
         } else {
@@ -1,4 +1,4 @@
-use std::{cell::Cell, mem::ManuallyDrop};
+use std::{cell::Cell, mem::ManuallyDrop, ptr};
 
 use log::{error, trace};
 use windows::{
@@ -7,8 +7,8 @@ use windows::{
         UI::TextServices::{
             GUID_PROP_ATTRIBUTE, ITfComposition, ITfCompositionSink, ITfContext,
             ITfContextComposition, ITfEditSession, ITfEditSession_Impl, ITfInsertAtSelection,
-            ITfRange, TF_AE_NONE, TF_ANCHOR_END, TF_ES_READWRITE, TF_IAS_QUERYONLY, TF_SELECTION,
-            TF_SELECTIONSTYLE, TF_ST_CORRECTION,
+            ITfRange, TF_AE_NONE, TF_ANCHOR_END, TF_ANCHOR_START, TF_ES_READWRITE,
+            TF_IAS_QUERYONLY, TF_SELECTION, TF_SELECTIONSTYLE, TF_ST_CORRECTION,
         },
     },
     core::{AsImpl, Interface, Result, VARIANT, implement},
@@ -91,6 +91,43 @@ pub fn end_composition(tid: u32, context: &ITfContext, composition: &ITfComposit
     }
 }
 
+/// Move the start of the composition back by `len` UTF-16 code units, taking over the text
+/// before it.
+pub fn extend_composition(
+    tid: u32,
+    context: &ITfContext,
+    composition: &ITfComposition,
+    len: usize,
+) -> Result<()> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        composition: &'a ITfComposition,
+        len: usize,
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self.composition.GetRange()?;
+                let mut shifted = 0;
+                range.ShiftStart(ec, -(self.len as i32), &mut shifted, ptr::null())?;
+                self.composition.ShiftStart(ec, &range)
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session { composition, len });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub fn set_text(
     tid: u32,
     context: &ITfContext,
@@ -155,6 +192,51 @@ pub fn set_text(
     }
 }
 
+/// Put the caret inside the range, `offset` UTF-16 code units after its start.
+pub fn set_caret(tid: u32, context: &ITfContext, range: ITfRange, offset: usize) -> Result<()> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        range: ITfRange,
+        offset: usize,
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                self.range.Collapse(ec, TF_ANCHOR_START)?;
+                let mut shifted = 0;
+                self.range
+                    .ShiftEnd(ec, self.offset as i32, &mut shifted, ptr::null())?;
+                self.range.Collapse(ec, TF_ANCHOR_END)?;
+                let selection = TF_SELECTION {
+                    range: ManuallyDrop::new(Some(self.range.clone())),
+                    style: TF_SELECTIONSTYLE {
+                        ase: TF_AE_NONE,
+                        fInterimChar: FALSE,
+                    },
+                };
+                self.context.SetSelection(ec, &[selection])
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session {
+        context,
+        range,
+        offset,
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub fn insert_text(tid: u32, context: &ITfContext, text: &[u16]) -> Result<()> {
     #[implement(ITfEditSession)]
     struct Session<'a> {
@@ -197,6 +279,104 @@ pub fn insert_text(tid: u32, context: &ITfContext, text: &[u16]) -> Result<()> {
     }
 }
 
+/// Insert the text after the caret, leaving the caret where it is.
+pub fn insert_text_after_caret(tid: u32, context: &ITfContext, text: &[u16]) -> Result<()> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        text: &'a [u16],
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                range.SetText(ec, TF_ST_CORRECTION, self.text)?;
+                range.Collapse(ec, TF_ANCHOR_START)?;
+                let selection = TF_SELECTION {
+                    range: ManuallyDrop::new(Some(range.clone())),
+                    style: TF_SELECTIONSTYLE {
+                        ase: TF_AE_NONE,
+                        fInterimChar: FALSE,
+                    },
+                };
+                self.context.SetSelection(ec, &[selection])
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session { context, text });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Move the caret over the char after it if it's the expected one. Returns whether it moved.
+pub fn step_over(tid: u32, context: &ITfContext, expected: &[u16]) -> Result<bool> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        expected: &'a [u16],
+        moved: Cell<bool>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                range.Collapse(ec, TF_ANCHOR_END)?;
+                let mut shifted = 0;
+                range.ShiftEnd(ec, self.expected.len() as i32, &mut shifted, ptr::null())?;
+                let mut text = vec![0; self.expected.len()];
+                let mut len = 0;
+                range.GetText(ec, 0, &mut text, &mut len)?;
+                if len as usize != self.expected.len() || text != self.expected {
+                    return Ok(());
+                }
+                range.Collapse(ec, TF_ANCHOR_END)?;
+                let selection = TF_SELECTION {
+                    range: ManuallyDrop::new(Some(range.clone())),
+                    style: TF_SELECTIONSTYLE {
+                        ase: TF_AE_NONE,
+                        fInterimChar: FALSE,
+                    },
+                };
+                self.context.SetSelection(ec, &[selection])?;
+                self.moved.set(true);
+                Ok(())
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session {
+        context,
+        expected,
+        moved: Cell::new(false),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            Ok(session.moved.get())
+        }
+    }
+}
+
 pub fn get_pos(tid: u32, context: &ITfContext, range: &ITfRange) -> Result<(i32, i32)> {
     #[implement(ITfEditSession)]
     struct Session<'a> {
@@ -234,3 +414,147 @@ pub fn get_pos(tid: u32, context: &ITfContext, range: &ITfRange) -> Result<(i32,
         }
     }
 }
+
+/// The caret, or the end of the selection, for it to be told later whether it has moved.
+pub fn get_caret(tid: u32, context: &ITfContext) -> Result<ITfRange> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        caret: Cell<Option<ITfRange>>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                range.Collapse(ec, TF_ANCHOR_END)?;
+                self.caret.set(Some(range));
+                Ok(())
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session {
+        context,
+        caret: Cell::new(None),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            Ok(session.caret.take().expect("Caret is None."))
+        }
+    }
+}
+
+/// Whether the caret is still where `get_caret` found it, with nothing selected.
+pub fn is_caret(tid: u32, context: &ITfContext, caret: &ITfRange) -> Result<bool> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        caret: &'a ITfRange,
+        same: Cell<bool>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                let same = range
+                    .IsEqualStart(ec, self.caret, TF_ANCHOR_START)?
+                    .as_bool()
+                    && range.IsEqualEnd(ec, self.caret, TF_ANCHOR_END)?.as_bool();
+                self.same.set(same);
+                Ok(())
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session {
+        context,
+        caret,
+        same: Cell::new(false),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            Ok(session.same.get())
+        }
+    }
+}
+
+/// Read at most `max` UTF-16 units before the range, or before the selection if `range` is
+/// `None`. A surrogate pair cut in half at the start is left out.
+pub fn get_preceding_text(
+    tid: u32,
+    context: &ITfContext,
+    range: Option<&ITfRange>,
+    max: usize,
+) -> Result<Vec<u16>> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        range: Option<&'a ITfRange>,
+        max: usize,
+        text: Cell<Vec<u16>>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = match self.range {
+                    Some(range) => range.Clone()?,
+                    None => self
+                        .context
+                        .cast::<ITfInsertAtSelection>()?
+                        .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?,
+                };
+                range.Collapse(ec, TF_ANCHOR_START)?;
+                let mut shifted = 0;
+                range.ShiftStart(ec, -(self.max as i32), &mut shifted, ptr::null())?;
+                let mut text = vec![0; self.max];
+                let mut len = 0;
+                range.GetText(ec, 0, &mut text, &mut len)?;
+                text.truncate(len as usize);
+                if text
+                    .first()
+                    .is_some_and(|unit| (0xDC00..0xE000).contains(unit))
+                {
+                    text.remove(0);
+                }
+                self.text.set(text);
+                Ok(())
+            }
+        }
+    }
+
+    let session = ITfEditSession::from(Session {
+        context,
+        range,
+        max,
+        text: Cell::new(Vec::new()),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            Ok(session.text.take())
+        }
+    }
+}
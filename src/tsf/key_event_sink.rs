@@ -1,4 +1,4 @@
-use std::ffi::OsString;
+use std::{ffi::OsString, time::Instant};
 
 use Input::*;
 use Shortcut::*;
@@ -19,9 +19,14 @@ use windows::{
 
 use super::{TextService, TextServiceInner, edit_session};
 use crate::{
-    conf::{self, Toggle},
+    conf::{self, Modifier, Toggle},
     extend::{CharExt, GUIDExt, OsStrExt2, VKExt},
+    keys::{self, Action, Key},
 };
+
+/// How many UTF-16 units before the caret are read to pair up quotes
+const PRECEDING_TEXT_LEN: usize = 256;
+
 //----------------------------------------------------------------------------
 //
 //  A "sink" for key events. From here on the processing begins.
@@ -52,10 +57,11 @@ impl ITfKeyEventSink_Impl for TextService {
     ) -> Result<BOOL> {
         trace!("OnTestKeyDown({:#04X})", wparam.0);
         let mut inner = self.write()?;
-        // track ctrl
-        inner.fresh_ctrl = is_ctrl(wparam);
+        // track the toggle
+        inner.tap_down(wparam.0 as u32, lparam.0 as u32);
         // detect shortcut
-        if let Some(shortcut) = Shortcut::try_from(wparam.0) {
+        let composing = inner.composition.is_some();
+        if let Some(shortcut) = Shortcut::try_from(wparam.0, composing) {
             return inner.test_shortcut(shortcut);
         }
         let input = inner.parse_input(wparam.0 as u32, lparam.0 as u32)?;
@@ -87,11 +93,15 @@ impl ITfKeyEventSink_Impl for TextService {
     ) -> Result<BOOL> {
         trace!("OnKeyDown({:#04X})", wparam.0);
         let mut inner = self.write()?;
-        inner.fresh_ctrl = is_ctrl(wparam);
-        if let Some(shortcut) = Shortcut::try_from(wparam.0) {
+        inner.tap_down(wparam.0 as u32, lparam.0 as u32);
+        let composing = inner.composition.is_some();
+        if let Some(shortcut) = Shortcut::try_from(wparam.0, composing) {
             return inner.handle_shortcut(shortcut);
         }
         let input = inner.parse_input(wparam.0 as u32, lparam.0 as u32)?;
+        if inner.release_if_unbound(input)? {
+            return Ok(FALSE);
+        }
         if inner.disabled_by_capslock() {
             inner.abort()?;
             return inner.handle_uppercase_input(input, context);
@@ -103,21 +113,15 @@ impl ITfKeyEventSink_Impl for TextService {
         inner.handle_input(input, context)
     }
 
-    /// Flip the modifiers back
+    /// Flip the toggle if it's tapped
     fn OnTestKeyUp(
         &self,
         _context: Option<&ITfContext>,
         wparam: WPARAM,
-        _lparam: LPARAM,
+        lparam: LPARAM,
     ) -> Result<BOOL> {
         trace!("OnTestKeyUp({:#04X})", wparam.0);
-        if is_ctrl(wparam) {
-            let mut inner = self.write()?;
-            if inner.fresh_ctrl {
-                inner.fresh_ctrl = false;
-                inner.disabled_by_ctrl = !inner.disabled_by_ctrl
-            }
-        }
+        self.write()?.tap_up(wparam.0 as u32, lparam.0 as u32)?;
         Ok(FALSE)
     }
 
@@ -125,16 +129,10 @@ impl ITfKeyEventSink_Impl for TextService {
         &self,
         _context: Option<&ITfContext>,
         wparam: WPARAM,
-        _lparam: LPARAM,
+        lparam: LPARAM,
     ) -> Result<BOOL> {
         trace!("OnKeyUp({:#04X})", wparam.0);
-        if is_ctrl(wparam) {
-            let mut inner = self.write()?;
-            if inner.fresh_ctrl {
-                inner.fresh_ctrl = false;
-                inner.disabled_by_ctrl = !inner.disabled_by_ctrl
-            }
-        }
+        self.write()?.tap_up(wparam.0 as u32, lparam.0 as u32)?;
         Ok(FALSE)
     }
 
@@ -149,20 +147,17 @@ impl ITfKeyEventSink_Impl for TextService {
 
     fn OnSetFocus(&self, foreground: BOOL) -> Result<()> {
         trace!("OnSetFocus({})", foreground.as_bool());
+        let mut inner = self.write()?;
+        // the keys may go up elsewhere
+        inner.tap.reset();
         if !foreground.as_bool() {
-            self.write()?.abort()
+            inner.abort()
         } else {
             Ok(())
         }
     }
 }
 
-fn is_ctrl(wparam: WPARAM) -> bool {
-    wparam.0 == VK_CONTROL.0 as usize
-        || wparam.0 == VK_LCONTROL.0 as usize
-        || wparam.0 == VK_RCONTROL.0 as usize
-}
-
 impl TextServiceInner {
     fn parse_input(&self, keycode: u32, scancode: u32) -> Result<Input> {
         // let hkl = self.hkl.ok_or(Error::HKLMissing)?;
@@ -172,10 +167,15 @@ impl TextServiceInner {
             0x09 => Tab,
             0x0D => Enter,
             0x20 => Space,
+            0x21 => PageUp,
+            0x22 => PageDown,
+            0x23 => End,
+            0x24 => Home,
             0x25 => Left,
             0x26 => Up,
             0x27 => Right,
             0x28 => Down,
+            0x2E => Delete,
             keycode @ 0x00..0x20 | keycode @ 0x7F => Unknown(keycode),
             keycode => {
                 let mut buf = [0; 8];
@@ -190,8 +190,14 @@ impl TextServiceInner {
                 let Ok(ch) = char::try_from_utf16(buf[0]) else {
                     return Ok(Unknown(keycode));
                 };
+                // the keys selecting candidates only do so while composing
+                if self.composition.is_some()
+                    && let Some(index) = conf::get().candidates.select(ch)
+                {
+                    return Ok(Select(index));
+                }
                 match ch {
-                    number @ '0'..='9' => Number(number as usize - '0' as usize),
+                    '0'..='9' => Number,
                     letter @ 'a'..='z' | letter @ 'A'..='Z' => Letter(letter),
                     punct => Punct(punct),
                 }
@@ -203,19 +209,42 @@ impl TextServiceInner {
 
 #[derive(Debug)]
 enum Shortcut {
-    NextSchema,
+    /// A shortcut bound to the action under `[keys]`, for the current state
+    Bound(Action),
+    SelectCandidate(usize),
     Undefined,
 }
 
 impl Shortcut {
-    fn try_from(key_code: usize) -> Option<Shortcut> {
+    fn try_from(key_code: usize, composing: bool) -> Option<Shortcut> {
         let ctrl = VK_CONTROL.is_down() || VK_LCONTROL.is_down() || VK_RCONTROL.is_down();
         let alt = VK_MENU.is_down();
         let shift = VK_SHIFT.is_down() || VK_LSHIFT.is_down() || VK_RSHIFT.is_down();
-        match (ctrl, alt, shift, key_code) {
-            (true, false, true, 0x4E) => Some(NextSchema), // Ctrl + Shift + N
-            (true, ..) | (_, true, ..) => Some(Undefined),
-            _ => None,
+        let modifier = match (ctrl, alt, shift) {
+            (true, false, false) => Modifier::Ctrl,
+            (false, true, false) => Modifier::Alt,
+            _ => Modifier::None,
+        };
+        if let Some(key) = Key::from_vk(key_code as u32) {
+            let pressed = keys::Shortcut {
+                ctrl,
+                alt,
+                shift,
+                key,
+            };
+            if let Some(action) = conf::get().keys.action(&pressed, composing) {
+                return Some(Bound(action));
+            }
+        }
+        // letters and numbers share their virtual key codes with their uppercase chars
+        if let Some(key) = char::from_u32(key_code as u32)
+            && let Some(index) = conf::get().candidates.select_held(modifier, key)
+        {
+            return Some(SelectCandidate(index));
+        }
+        match ctrl || alt {
+            true => Some(Undefined),
+            false => None,
         }
     }
 }
@@ -225,16 +254,24 @@ impl Shortcut {
 #[derive(Debug, Clone, Copy)]
 enum Input {
     Letter(char),
-    Number(usize),
+    /// A digit that doesn't select candidates
+    Number,
     Punct(char),
+    /// A key selecting the candidate on the page
+    Select(usize),
     Space,
     Backspace,
+    Delete,
     Enter,
     Tab,
     Left,
     Up,
     Right,
     Down,
+    Home,
+    End,
+    PageUp,
+    PageDown,
     Unknown(#[allow(dead_code)] u32),
 }
 
@@ -251,10 +288,15 @@ impl TextServiceInner {
         if self.composition.is_none() {
             match input {
                 Letter(_) | Punct(_) | Space => Ok(TRUE),
+                Backspace => Ok(self.recomposable().into()),
                 _ => Ok(FALSE),
             }
         } else {
-            Ok(TRUE)
+            match input {
+                // unless they are bound under `[keys]`
+                Space | Enter | Tab => Ok(FALSE),
+                _ => Ok(TRUE),
+            }
         }
     }
 
@@ -272,38 +314,75 @@ impl TextServiceInner {
                     self.start_composition()?;
                     self.push(letter)?
                 }
+                Punct(punct) if self.lookup_schema(punct).is_some() => {
+                    let schema = self.lookup_schema(punct).unwrap();
+                    self.start_lookup(schema)?
+                }
                 Punct(punct) => {
-                    let ch = self.engine.remap_punct(punct);
-                    self.insert_char(ch)?
+                    let preceding = self.preceding_text();
+                    let ch = self.engine.render_punct(punct, preceding.as_deref());
+                    self.insert_punct(ch, preceding.as_deref())?
                 }
                 Space => {
-                    let ch = self.engine.remap_punct(' ');
+                    let ch = self.engine.render_punct(' ', None);
                     self.insert_char(ch)?
                 }
-                _ => return Ok(FALSE),
+                // bring back what was just committed, or let the client delete if it's gone
+                Backspace if self.recomposable() => {
+                    return Ok(self.recompose()?.into());
+                }
+                // the caret may move or the text may change without the input method knowing
+                _ => {
+                    self.just_committed = None;
+                    self.last_commit = None;
+                    return Ok(FALSE);
+                }
             }
         } else {
             match input {
                 Letter(letter) => self.push(letter)?,
-                Number(0) => (),
-                Number(number) => self.select(number - 1)?,
+                Select(index) => self.select_on_page(index)?,
+                Number => (),
+                // typing the prefix twice releases the prefix itself
+                Punct(punct) if self.spelling.is_empty() && self.engine.looking_up() => {
+                    self.force_release(punct)?
+                }
+                // or keep the sentences going
+                Punct(punct)
+                    if self.engine.multiple_sentences() && self.engine.is_sentence_punct(punct) =>
+                {
+                    self.push(punct)?
+                }
                 Punct(punct) => {
-                    let remmaped = self.engine.remap_punct(punct);
+                    let preceding = self.preceding_text();
+                    let remmaped = self.engine.render_punct(punct, preceding.as_deref());
                     if remmaped.is_joiner() {
                         self.push(punct)?;
                     } else {
                         self.force_commit(remmaped)?;
+                        self.close_bracket(remmaped)?;
                     }
                 }
-                Space => self.commit()?,
-                Enter => self.release()?,
                 Backspace => self.pop()?,
-                Tab => {
-                    self.push(' ')?;
-                    self.release()?
+                Delete => self.delete()?,
+                // released beforehand unless they are bound under `[keys]`
+                Space | Enter | Tab => (),
+                // the arrows along the candidate list move the highlight
+                Up if conf::get().layout.vertical => self.focus_previous()?,
+                Down if conf::get().layout.vertical => self.focus_next()?,
+                Left if !conf::get().layout.vertical && !self.suggestions.is_empty() => {
+                    self.focus_previous()?
+                }
+                Right if !conf::get().layout.vertical && !self.suggestions.is_empty() => {
+                    self.focus_next()?
                 }
-                // disable cursor movement because I am lazy.
-                Left | Up | Right | Down => (),
+                // and the others turn pages or move the caret
+                Up | PageUp => self.previous_page()?,
+                Down | PageDown => self.next_page()?,
+                Left => self.caret_left()?,
+                Right => self.caret_right()?,
+                Home => self.caret_home()?,
+                End => self.caret_end()?,
                 Unknown(_) => {
                     return Ok(FALSE);
                 }
@@ -312,36 +391,150 @@ impl TextServiceInner {
         Ok(TRUE)
     }
 
+    /// Space, Enter and Tab that aren't bound under `[keys]` release the composition and then go
+    /// on to the client as if nothing was composed, instead of being eaten. Returns whether the
+    /// composition is released.
+    fn release_if_unbound(&mut self, input: Input) -> Result<bool> {
+        if self.composition.is_none() || !matches!(input, Space | Enter | Tab) {
+            return Ok(false);
+        }
+        self.perform(Action::Release)?;
+        Ok(true)
+    }
+
+    /// The schema to look up in if `punct` is the configured prefix and the schema exists.
+    fn lookup_schema(&self, punct: char) -> Option<&'static str> {
+        let lookup = conf::get().lookup.as_ref()?;
+        if lookup.prefix != punct {
+            return None;
+        }
+        if !self.engine.has_schema(&lookup.schema) {
+            warn!("Schema '{}' for lookup is not found.", lookup.schema);
+            return None;
+        }
+        Some(&lookup.schema)
+    }
+
+    /// The text before the caret for quotes to be paired by. While composing, it's the text before
+    /// the composition followed by what the composition would commit. `None` if the document
+    /// can't be read.
+    fn preceding_text(&self) -> Option<String> {
+        let range = match self.composition.as_ref() {
+            Some(composition) => Some(unsafe { composition.GetRange() }.ok()?),
+            None => None,
+        };
+        let text = edit_session::get_preceding_text(
+            self.tid,
+            self.context().ok()?,
+            range.as_ref(),
+            PRECEDING_TEXT_LEN,
+        )
+        .ok()?;
+        let mut text = String::from_utf16_lossy(&text);
+        if self.composition.is_some() {
+            text.push_str(&self.selected);
+            match self.suggestions.get(self.focus) {
+                Some(sugg) => text.push_str(&sugg.output),
+                None => text.push_str(&self.spelling),
+            }
+        }
+        Some(text)
+    }
+
     fn insert_char(&mut self, ch: char) -> Result<()> {
+        self.just_committed = None;
         self.char_buf.clear();
         self.char_buf.push(ch);
         let text = OsString::from(&self.char_buf).to_wchars();
         edit_session::insert_text(self.tid, self.context()?, &text)
     }
 
-    fn test_shortcut(&self, shortcut: Shortcut) -> Result<BOOL> {
-        if self.composition.is_none() {
-            match shortcut {
-                NextSchema => Ok(TRUE),
-                _ => Ok(FALSE),
+    /// Insert the punctuator, closing the brackets left open if it ends a sentence.
+    fn insert_punct(&mut self, ch: char, preceding: Option<&str>) -> Result<()> {
+        self.just_committed = None;
+        if conf::get().behavior.auto_close && self.engine.is_closer(ch) {
+            self.char_buf.clear();
+            self.char_buf.push(ch);
+            let text = OsString::from(&self.char_buf).to_wchars();
+            if edit_session::step_over(self.tid, self.context()?, &text)? {
+                return Ok(());
             }
-        } else {
-            Ok(FALSE)
+        }
+        self.char_buf.clear();
+        if self.engine.ends_sentence(ch)
+            && let Some(preceding) = preceding
+        {
+            let closers = self.engine.closers(preceding);
+            self.char_buf.push_str(&closers);
+        }
+        self.char_buf.push(ch);
+        let text = OsString::from(&self.char_buf).to_wchars();
+        edit_session::insert_text(self.tid, self.context()?, &text)?;
+        self.close_bracket(ch)
+    }
+
+    /// Insert the closer after the caret if `auto_close` is enabled and the char is an opener.
+    fn close_bracket(&mut self, opener: char) -> Result<()> {
+        if !conf::get().behavior.auto_close {
+            return Ok(());
+        }
+        let Some(closer) = self.engine.closer(opener) else {
+            return Ok(());
+        };
+        self.char_buf.clear();
+        self.char_buf.push(closer);
+        let text = OsString::from(&self.char_buf).to_wchars();
+        edit_session::insert_text_after_caret(self.tid, self.context()?, &text)
+    }
+
+    fn test_shortcut(&self, shortcut: Shortcut) -> Result<BOOL> {
+        match shortcut {
+            Bound(Action::UndoCommit) => Ok(self.undoable().into()),
+            Bound(_) => Ok(TRUE),
+            SelectCandidate(_) => Ok(self.composition.is_some().into()),
+            Undefined => Ok(FALSE),
         }
     }
 
     fn handle_shortcut(&mut self, shortcut: Shortcut) -> Result<BOOL> {
-        if self.composition.is_none() {
-            match shortcut {
-                NextSchema => {
-                    self.engine.next_schema();
-                    Ok(TRUE)
-                }
-                _ => Ok(FALSE),
+        match shortcut {
+            Bound(action) => self.perform(action),
+            SelectCandidate(index) if self.composition.is_some() => {
+                self.select_on_page(index)?;
+                Ok(TRUE)
             }
-        } else {
-            Ok(FALSE)
+            _ => Ok(FALSE),
+        }
+    }
+
+    /// Do what the shortcut is bound to. The actions for compositions are only bound while
+    /// composing, and the others while not.
+    fn perform(&mut self, action: Action) -> Result<BOOL> {
+        match action {
+            Action::NextSchema => self.engine.next_schema(),
+            Action::PreviousSchema => self.engine.previous_schema(),
+            Action::UndoCommit => return Ok(self.undo_commit()?.into()),
+            Action::ToggleSentences => self.engine.toggle_sentences(),
+            Action::Commit => self.commit()?,
+            // several sentences are committed as a whole
+            Action::Release
+                if self
+                    .spelling
+                    .contains(|ch| self.engine.is_sentence_punct(ch)) =>
+            {
+                self.commit()?
+            }
+            Action::Release => self.release()?,
+            Action::ReleaseWithSpace => {
+                self.caret = self.spelling.len();
+                self.push(' ')?;
+                self.release()?
+            }
+            Action::Clear => self.clear()?,
+            Action::CaretLeft => self.caret_left()?,
+            Action::CaretRight => self.caret_right()?,
         }
+        Ok(TRUE)
     }
 }
 
@@ -356,7 +549,9 @@ impl TextServiceInner {
 impl TextServiceInner {
     fn disabled_naively(&self) -> bool {
         match conf::get().behavior.toggle {
-            Some(Toggle::Ctrl) => self.disabled_by_ctrl,
+            Some(Toggle::Ctrl | Toggle::Shift | Toggle::LShift | Toggle::RShift) => {
+                self.disabled_by_tap
+            }
             Some(Toggle::Eisu) => VK_KANJI.is_toggled(),
             Some(Toggle::CapsLock) | None => false,
         }
@@ -364,9 +559,33 @@ impl TextServiceInner {
 
     fn disabled_by_capslock(&self) -> bool {
         match conf::get().behavior.toggle {
-            Some(Toggle::Ctrl) | Some(Toggle::Eisu) | None => false,
             Some(Toggle::CapsLock) => VK_CAPITAL.is_toggled(),
+            _ => false,
+        }
+    }
+
+    fn tap_down(&mut self, key_code: u32, lparam: u32) {
+        let Some(key) = conf::get().behavior.toggle.and_then(Toggle::tap_key) else {
+            return;
+        };
+        let tapped = key.matches(key_code, lparam);
+        self.tap.key_down(key_code, tapped, Instant::now());
+    }
+
+    /// Flip the toggle if it's tapped on its own. What's typed so far is released as it is.
+    fn tap_up(&mut self, key_code: u32, lparam: u32) -> Result<()> {
+        let Some(key) = conf::get().behavior.toggle.and_then(Toggle::tap_key) else {
+            return Ok(());
+        };
+        let tapped = key.matches(key_code, lparam);
+        if !self.tap.key_up(key_code, tapped, Instant::now()) {
+            return Ok(());
+        }
+        self.disabled_by_tap = !self.disabled_by_tap;
+        if self.disabled_by_tap && self.composition.is_some() {
+            self.release()?;
         }
+        Ok(())
     }
 
     fn test_uppercase_input(&self, input: Input) -> Result<BOOL> {
@@ -17,8 +17,8 @@ use windows::{
         UI::{
             TextServices::{
                 HKL, ITfComposition, ITfCompositionSink, ITfContext, ITfDisplayAttributeProvider,
-                ITfKeyEventSink, ITfLangBarItem, ITfTextInputProcessor, ITfTextInputProcessorEx,
-                ITfThreadMgr, ITfThreadMgrEventSink,
+                ITfKeyEventSink, ITfLangBarItem, ITfRange, ITfTextInputProcessor,
+                ITfTextInputProcessorEx, ITfThreadMgr, ITfThreadMgrEventSink,
             },
             WindowsAndMessaging::HICON,
         },
@@ -26,9 +26,12 @@ use windows::{
     core::{AsImpl, Interface, Result, VARIANT, implement},
 };
 
+use self::composition::{Commit, Selection};
 use crate::{
+    conf,
     engine::{Engine, Suggestion},
     global::hkl_or_us,
+    keys::Tap,
     ui::candidate_list::CandidateList,
 };
 
@@ -68,13 +71,28 @@ struct TextServiceInner {
     // KeyEventSink
     hkl: HKL,
     char_buf: String,
-    fresh_ctrl: bool,
-    disabled_by_ctrl: bool,
+    /// Tells if the toggle is tapped
+    tap: Tap,
+    disabled_by_tap: bool,
     // Composition
     composition: Option<ITfComposition>,
     spelling: String,
+    /// Where the caret is in the spelling, in bytes
+    caret: usize,
     selected: String,
+    /// The text before the composition followed by the selected part
+    preceding: String,
+    /// How many UTF-16 code units before the composition are to be replaced on commit
+    replaced: usize,
+    /// The caret right after the last commit, if it can be respelled. The commit can be composed
+    /// again as long as the caret stays there
+    just_committed: Option<ITfRange>,
+    /// The states before each candidate selected, for Backspace to go back to
+    selections: Vec<Selection>,
+    last_commit: Option<Commit>,
     suggestions: Vec<Suggestion>,
+    /// Which suggestion is highlighted in the candidate list
+    focus: usize,
     preedit: String,
     // display attribute provider
     display_attribute: Option<VARIANT>,
@@ -95,13 +113,20 @@ impl TextService {
             context: None,
             hkl: hkl_or_us(),
             char_buf: String::with_capacity(4),
-            fresh_ctrl: false,
-            disabled_by_ctrl: false,
+            tap: Tap::new(Duration::from_millis(conf::get().behavior.tap_timeout)),
+            disabled_by_tap: false,
             cookie: None,
             composition: None,
             spelling: String::with_capacity(32),
+            caret: 0,
             suggestions: Vec::new(),
+            focus: 0,
             selected: String::with_capacity(32),
+            preceding: String::with_capacity(64),
+            replaced: 0,
+            just_committed: None,
+            selections: Vec::new(),
+            last_commit: None,
             preedit: String::with_capacity(32),
             icon: HICON::default(),
             candidate_list: None,
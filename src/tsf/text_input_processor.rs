@@ -13,7 +13,7 @@ use windows::{
 };
 
 use super::TextService;
-use crate::{DISPLAY_ATTR_ID};
+use crate::{DISPLAY_ATTR_ID, font};
 
 #[allow(non_snake_case)]
 impl ITfTextInputProcessor_Impl for TextService {
@@ -23,6 +23,7 @@ impl ITfTextInputProcessor_Impl for TextService {
         let thread_mgr = thread_mgr.ok_or(E_FAIL)?;
         inner.tid = tid;
         inner.thread_mgr = Some(thread_mgr.clone());
+        font::warm_up();
         unsafe {
             // Use self as event sink to subscribe to events
             thread_mgr.cast::<ITfKeystrokeMgr>()?.AdviseKeyEventSink(
@@ -26,11 +26,10 @@ use windows::{
 };
 
 use crate::{
-    CANDI_INDEX_SUFFIX, CANDI_INDEX_SUFFIX_MONO, CANDI_INDEXES,
     conf::{self},
     engine::Suggestion,
     extend::{ColorExt, OsStrExt2},
-    global,
+    font, global,
 };
 
 const WINDOW_CLASS: PCSTR = s!("CANDIDATE_LIST");
@@ -177,12 +176,18 @@ impl CandidateList {
                 index_font = candi_font;
             }
 
-            // TODO this is no reliable at all
-            let font_name = conf.font.name.to_ascii_lowercase();
-            let index_suffix = if font_name.contains("mono") || font_name.contains("fairfax") {
-                CANDI_INDEX_SUFFIX_MONO
+            // guess by the name if the font file is unavailable
+            let monospace = match font::get() {
+                Some(font) => font.monospace(),
+                None => {
+                    let font_name = conf.font.name.to_ascii_lowercase();
+                    font_name.contains("mono") || font_name.contains("fairfax")
+                }
+            };
+            let index_suffix = if monospace {
+                conf.candidates.suffix.trim_end()
             } else {
-                CANDI_INDEX_SUFFIX
+                conf.candidates.suffix.as_str()
             };
             ReleaseDC(window, dc);
             Ok(CandidateList {
@@ -211,7 +216,15 @@ impl CandidateList {
         Ok(())
     }
 
-    pub fn show(&self, suggs: &[Suggestion]) -> Result<()> {
+    /// Show a page of suggestions with the one at `highlighted` highlighted. The page number is
+    /// shown if there are other pages.
+    pub fn show(
+        &self,
+        suggs: &[Suggestion],
+        highlighted: usize,
+        page: usize,
+        more: bool,
+    ) -> Result<()> {
         unsafe {
             let conf = conf::get();
             let mut indice = Vec::with_capacity(suggs.len());
@@ -226,7 +239,7 @@ impl CandidateList {
             let dc: HDC = GetDC(self.window);
             for (index, sugg) in suggs.iter().enumerate() {
                 let mut size = SIZE::default();
-                let index = format!("{}{}", CANDI_INDEXES[index], self.index_suffix);
+                let index = format!("{}{}", conf.candidates.label(index), self.index_suffix);
                 let index = OsString::from(index).to_wchars();
                 SelectObject(dc, self.index_font);
                 GetTextExtentPoint32W(dc, &index, &mut size);
@@ -242,6 +255,18 @@ impl CandidateList {
                 candi_widths.push(size.cx);
                 candis.push(candi);
             }
+            // the page indicator
+            let mut indicator = Vec::new();
+            let mut indicator_width = 0;
+            if page != 0 || more {
+                let prev = if page != 0 { "<" } else { " " };
+                let next = if more { ">" } else { " " };
+                indicator = OsString::from(format!("{prev}{}{next}", page + 1)).to_wchars();
+                let mut size = SIZE::default();
+                SelectObject(dc, self.index_font);
+                GetTextExtentPoint32W(dc, &indicator, &mut size);
+                indicator_width = size.cx;
+            }
             ReleaseDC(self.window, dc);
             let row_height = max(candi_height, index_height);
             let label_height = LABEL_PADDING_TOP + row_height + LABEL_PADDING_BOTTOM;
@@ -250,10 +275,12 @@ impl CandidateList {
             if conf.layout.vertical {
                 let candi_num: i32 = suggs.len().try_into().unwrap();
                 wnd_height += candi_num * label_height;
+                if !indicator.is_empty() {
+                    wnd_height += label_height;
+                }
                 wnd_width += CLIP_WIDTH
                     + LABEL_PADDING_LEFT
-                    + index_width
-                    + candi_width
+                    + max(index_width + candi_width, indicator_width)
                     + LABEL_PADDING_RIGHT;
                 wnd_width = max(wnd_width, wnd_height * 4 / 5)
             } else {
@@ -264,20 +291,37 @@ impl CandidateList {
                     wnd_width += index_width;
                     wnd_width += candi_width;
                 }
+                if !indicator.is_empty() {
+                    wnd_width += LABEL_PADDING_LEFT + indicator_width + LABEL_PADDING_RIGHT;
+                }
             }
             wnd_height += BORDER_WIDTH * 2;
             wnd_width += BORDER_WIDTH * 2;
 
-            let highlight_width = if conf.layout.vertical {
-                wnd_width - CLIP_WIDTH - BORDER_WIDTH * 2
+            let highlight_width;
+            let mut highlight_x = 0;
+            let mut highlight_y = 0;
+            if conf.layout.vertical {
+                highlight_width = wnd_width - CLIP_WIDTH - BORDER_WIDTH * 2;
+                highlight_y = label_height * highlighted as i32;
             } else {
-                LABEL_PADDING_LEFT + index_width + candi_widths[0] + LABEL_PADDING_RIGHT
-            };
+                highlight_width = LABEL_PADDING_LEFT
+                    + index_width
+                    + candi_widths[highlighted]
+                    + LABEL_PADDING_RIGHT;
+                for candi_width in candi_widths[..highlighted].iter() {
+                    highlight_x +=
+                        LABEL_PADDING_LEFT + index_width + candi_width + LABEL_PADDING_RIGHT;
+                }
+            }
 
             // passing extra args to WndProc
             let arg = PaintArg {
                 wnd_width,
                 wnd_height,
+                highlighted,
+                highlight_x,
+                highlight_y,
                 highlight_width,
                 label_height,
                 row_height,
@@ -287,6 +331,7 @@ impl CandidateList {
                 candi_height,
                 candis,
                 indice,
+                indicator,
                 index_font: self.index_font,
                 candi_font: self.candi_font,
             };
@@ -323,6 +368,9 @@ impl CandidateList {
 struct PaintArg {
     wnd_width: i32,
     wnd_height: i32,
+    highlighted: usize,
+    highlight_x: i32,
+    highlight_y: i32,
     highlight_width: i32,
     label_height: i32,
     row_height: i32,
@@ -334,6 +382,8 @@ struct PaintArg {
     candi_font: HFONT,
     indice: Vec<Vec<u16>>,
     candis: Vec<Vec<u16>>,
+    /// Empty if there's only one page
+    indicator: Vec<u16>,
 }
 impl PaintArg {
     fn into_long_ptr(self) -> LongPointer {
@@ -378,7 +428,7 @@ fn paint(window: HWND) -> LRESULT {
         FillRect(
             dc,
             BORDER_WIDTH,
-            BORDER_WIDTH,
+            BORDER_WIDTH + arg.highlight_y,
             CLIP_WIDTH,
             arg.label_height,
             &conf.color.clip,
@@ -386,53 +436,37 @@ fn paint(window: HWND) -> LRESULT {
         // highlight
         FillRect(
             dc,
-            BORDER_WIDTH + CLIP_WIDTH,
-            BORDER_WIDTH,
+            BORDER_WIDTH + CLIP_WIDTH + arg.highlight_x,
+            BORDER_WIDTH + arg.highlight_y,
             arg.highlight_width,
             arg.label_height,
             &conf.color.highlight,
         );
+        SetBkMode(dc, TRANSPARENT);
     }
 
-    // highlighted text
     let mut index_x = BORDER_WIDTH + CLIP_WIDTH + LABEL_PADDING_LEFT;
     let mut candi_x = BORDER_WIDTH + index_x + arg.index_width;
     let mut index_y = BORDER_WIDTH + LABEL_PADDING_TOP + (arg.row_height - arg.index_height) / 2;
     let mut candi_y = BORDER_WIDTH + LABEL_PADDING_TOP + (arg.row_height - arg.candi_height) / 2;
-    unsafe {
-        SetBkMode(dc, TRANSPARENT);
-        TextOut(
-            dc,
-            index_x,
-            index_y,
-            &arg.indice[0],
-            &conf.color.index,
-            arg.index_font,
-        );
-        TextOut(
-            dc,
-            candi_x,
-            candi_y,
-            &arg.candis[0],
-            &conf.color.highlighted,
-            arg.candi_font,
-        );
-    }
-    // normal text
-    for i in 1..arg.candis.len() {
+    // move on from the label of the i-th candidate to the next one
+    let next_label = |x: &mut i32, y: &mut i32, i: usize| {
         if conf.layout.vertical {
-            index_y += arg.label_height;
-            candi_y += arg.label_height;
+            *y += arg.label_height;
         } else {
-            index_x += arg.index_width
-                + arg.candi_widths[i - 1]
-                + LABEL_PADDING_LEFT
-                + LABEL_PADDING_RIGHT;
-            candi_x += arg.index_width
-                + arg.candi_widths[i - 1]
-                + LABEL_PADDING_LEFT
-                + LABEL_PADDING_RIGHT;
+            *x += arg.index_width + arg.candi_widths[i] + LABEL_PADDING_LEFT + LABEL_PADDING_RIGHT;
+        }
+    };
+    for i in 0..arg.candis.len() {
+        if i != 0 {
+            next_label(&mut index_x, &mut index_y, i - 1);
+            next_label(&mut candi_x, &mut candi_y, i - 1);
         }
+        let color = if i == arg.highlighted {
+            &conf.color.highlighted
+        } else {
+            &conf.color.candidate
+        };
         unsafe {
             TextOut(
                 dc,
@@ -442,13 +476,20 @@ fn paint(window: HWND) -> LRESULT {
                 &conf.color.index,
                 arg.index_font,
             );
+            TextOut(dc, candi_x, candi_y, &arg.candis[i], color, arg.candi_font);
+        }
+    }
+    // page indicator after the last one
+    if !arg.indicator.is_empty() {
+        next_label(&mut index_x, &mut index_y, arg.candis.len() - 1);
+        unsafe {
             TextOut(
                 dc,
-                candi_x,
-                candi_y,
-                &arg.candis[i],
-                &conf.color.candidate,
-                arg.candi_font,
+                index_x,
+                index_y,
+                &arg.indicator,
+                &conf.color.index,
+                arg.index_font,
             );
         }
     }
@@ -474,7 +515,7 @@ unsafe fn FillRect(hdc: HDC, x: i32, y: i32, width: i32, height: i32, color: &Co
         left: x,
         top: y,
         right: x + width,
-        bottom: height,
+        bottom: y + height,
     };
     unsafe { Gdi::FillRect(hdc, &rect, color.to_hbrush()) };
 }